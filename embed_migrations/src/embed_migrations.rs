@@ -1,10 +1,9 @@
-use proc_macro2;
-use syn;
-
 use crate::migrations::migration_directory_from_given_path;
 use crate::util;
+use migrations_shared::error::Error as MigrationError;
+use migrations_shared::files::*;
 use std::error::Error;
-use std::fs::DirEntry;
+use std::fs;
 use std::path::Path;
 
 pub fn derive_embed_migrations(input: &syn::DeriveInput) -> proc_macro2::TokenStream {
@@ -16,12 +15,35 @@ pub fn derive_embed_migrations(input: &syn::DeriveInput) -> proc_macro2::TokenSt
     }
 
     let options = util::get_options_from_input(&parse_quote!(embed_migrations_options), &input.attrs, bug);
-    let migrations_path_opt = options.as_ref().map(|o| util::get_option(o, "migrations_path", bug));
-    let migrations_expr = migration_directory_from_given_path(migrations_path_opt.as_ref().map(String::as_str))
+    let migrations_path_opt = options
+        .as_ref()
+        .and_then(|o| util::get_optional_option(o, "migrations_path"));
+    let table_name_opt = options
+        .as_ref()
+        .and_then(|o| util::get_optional_option(o, "table_name"));
+    let table_schema_opt = options
+        .as_ref()
+        .and_then(|o| util::get_optional_option(o, "table_schema"));
+    let migrations_expr = migration_directory_from_given_path(migrations_path_opt.as_deref())
         .and_then(|path| migration_literals_from_path(&path));
     let migrations_expr = match migrations_expr {
         Ok(v) => v,
-        Err(e) => panic!("Error reading migrations: {}", e),
+        Err(e) => match e.downcast_ref::<MigrationError>() {
+            Some(e @ MigrationError::DuplicateMigrationVersion(..)) => {
+                let message = e.to_string();
+                return quote!(compile_error!(#message););
+            }
+            _ => panic!("Error reading migrations: {}", e),
+        },
+    };
+
+    let table_name_expr = match table_name_opt {
+        Some(table_name) => quote!(config.table_name = #table_name.to_string();),
+        None => quote!(),
+    };
+    let table_schema_expr = match table_schema_opt {
+        Some(table_schema) => quote!(config.table_schema = Some(#table_schema.to_string());),
+        None => quote!(),
     };
 
     // These are split into multiple `quote!` calls to avoid recursion limit
@@ -29,6 +51,8 @@ pub fn derive_embed_migrations(input: &syn::DeriveInput) -> proc_macro2::TokenSt
         struct EmbeddedMigration {
             version: &'static str,
             up_sql: &'static str,
+            down_sql: Option<&'static str>,
+            repeatable: bool,
         }
 
         #[async_trait]
@@ -42,30 +66,154 @@ pub fn derive_embed_migrations(input: &syn::DeriveInput) -> proc_macro2::TokenSt
                 Result::<(), RunMigrationsError>::Ok(())
             }
 
-            async fn revert(&self, _transaction: &DbTransaction<'_>) -> Result<(), RunMigrationsError> {
-                unreachable!()
+            async fn revert(&self, transaction: &DbTransaction<'_>) -> Result<(), RunMigrationsError> {
+                match self.down_sql {
+                    Some(down_sql) => {
+                        transaction.batch_execute(down_sql).await?;
+                        Result::<(), RunMigrationsError>::Ok(())
+                    }
+                    None => Err(RunMigrationsError::IrreversibleMigration(self.version.to_string())),
+                }
+            }
+
+            fn up_sql(&self) -> Result<Option<String>, RunMigrationsError> {
+                Ok(Some(self.up_sql.to_string()))
+            }
+
+            fn down_sql(&self) -> Result<Option<String>, RunMigrationsError> {
+                Ok(self.down_sql.map(str::to_string))
+            }
+
+            fn irreversible(&self) -> Result<bool, RunMigrationsError> {
+                Ok(self.down_sql.is_none() || has_irreversible_header(self.up_sql))
+            }
+
+            fn repeatable(&self) -> bool {
+                self.repeatable
             }
         }
     );
 
+    let config_fns = quote!(
+        /// The default runner configuration, with the migrations table given to `embed_migrations!`
+        #[allow(unused_mut)]
+        pub fn config() -> MigrationsConfig {
+            let mut config = MigrationsConfig::default();
+            #table_name_expr
+            #table_schema_expr
+            config
+        }
+    );
+
     let run_fns = quote!(
-        pub async fn run(pool: DbConnectionPool) -> Result<(), RunMigrationsError> {
-            run_with_output(pool, &mut io::sink()).await
+        pub async fn run<S: ConnectionSource>(source: S) -> Result<(), RunMigrationsError> {
+            run_with_output(source, &mut io::sink()).await
         }
 
-        pub async fn run_with_output(
-            pool: DbConnectionPool,
+        pub async fn run_with_output<S: ConnectionSource>(
+            source: S,
             out: &mut (dyn io::Write + Send + Sync),
         ) -> Result<(), RunMigrationsError> {
-            run_migrations(pool, ALL_MIGRATIONS.iter().map(|v| *v).collect(), out).await
+            run_with_observer(source, config(), &WriteObserver::new(out)).await
+        }
+
+        /// Runs the pending migrations with the given configuration, used as is. Start from
+        /// `config()` to keep the migrations table given to `embed_migrations!`.
+        pub async fn run_with_config<S: ConnectionSource>(
+            source: S,
+            config: MigrationsConfig,
+            out: &mut (dyn io::Write + Send + Sync),
+        ) -> Result<(), RunMigrationsError> {
+            run_with_observer(source, config, &WriteObserver::new(out)).await
+        }
+
+        /// Same as `run_with_config`, reporting progress to the given observer
+        pub async fn run_with_observer<S: ConnectionSource>(
+            source: S,
+            config: MigrationsConfig,
+            observer: &(dyn MigrationObserver + Sync),
+        ) -> Result<(), RunMigrationsError> {
+            run_migrations_with_config(source, ALL_MIGRATIONS.to_vec(), config, observer).await
+        }
+    );
+
+    let revert_fns = quote!(
+        pub async fn revert_latest<S: ConnectionSource>(source: S) -> Result<String, RunMigrationsError> {
+            revert_latest_with_output(source, &mut io::sink()).await
+        }
+
+        pub async fn revert_latest_with_output<S: ConnectionSource>(
+            source: S,
+            out: &mut (dyn io::Write + Send + Sync),
+        ) -> Result<String, RunMigrationsError> {
+            revert_latest_migration_with_config(source, ALL_MIGRATIONS.to_vec(), config(), &WriteObserver::new(out))
+                .await
+        }
+
+        pub async fn revert_to<S: ConnectionSource>(
+            source: S,
+            version: &str,
+        ) -> Result<Vec<String>, RunMigrationsError> {
+            revert_to_with_output(source, version, &mut io::sink()).await
+        }
+
+        pub async fn revert_to_with_output<S: ConnectionSource>(
+            source: S,
+            version: &str,
+            out: &mut (dyn io::Write + Send + Sync),
+        ) -> Result<Vec<String>, RunMigrationsError> {
+            revert_to_version_with_config(
+                source,
+                ALL_MIGRATIONS.to_vec(),
+                config(),
+                version,
+                &WriteObserver::new(out),
+            )
+            .await
+        }
+
+        pub async fn redo<S: ConnectionSource>(source: S) -> Result<String, RunMigrationsError> {
+            redo_with_output(source, &mut io::sink()).await
+        }
+
+        pub async fn redo_with_output<S: ConnectionSource>(
+            source: S,
+            out: &mut (dyn io::Write + Send + Sync),
+        ) -> Result<String, RunMigrationsError> {
+            redo_latest_with_config(source, ALL_MIGRATIONS.to_vec(), config(), &WriteObserver::new(out)).await
+        }
+
+        pub async fn revert_last<S: ConnectionSource>(
+            source: S,
+            count: usize,
+        ) -> Result<Vec<String>, RunMigrationsError> {
+            revert_last_with_output(source, count, &mut io::sink()).await
+        }
+
+        pub async fn revert_last_with_output<S: ConnectionSource>(
+            source: S,
+            count: usize,
+            out: &mut (dyn io::Write + Send + Sync),
+        ) -> Result<Vec<String>, RunMigrationsError> {
+            revert_last_with_config(
+                source,
+                ALL_MIGRATIONS.to_vec(),
+                config(),
+                count,
+                &WriteObserver::new(out),
+            )
+            .await
         }
     );
 
     quote! {
         use postgres_migrations::*;
-        use postgres_migrations::migration::{Migration};
+        use postgres_migrations::migration::{has_irreversible_header, Migration};
+        use postgres_migrations::observer::{MigrationObserver, WriteObserver};
         use postgres_migrations::error::Error as RunMigrationsError;
-        use postgres_migrations::runner::{DbTransaction, DbConnectionPool};
+        use postgres_migrations::connection::ConnectionSource;
+        use postgres_migrations::runner::{DbTransaction, MigrationsConfig};
+        use postgres_migrations::status::MigrationStatus;
 
         use std::io;
 
@@ -73,28 +221,82 @@ pub fn derive_embed_migrations(input: &syn::DeriveInput) -> proc_macro2::TokenSt
 
         #embedded_migration_def
 
+        #config_fns
+
         #run_fns
+
+        #revert_fns
+
+        /// The embedded migrations, e.g. to combine them with other migrations in a `MigrationSet`
+        pub fn migrations() -> Vec<&'static (dyn Migration + Send + Sync)> {
+            ALL_MIGRATIONS.to_vec()
+        }
+
+        pub async fn status<S: ConnectionSource>(source: S) -> Result<MigrationStatus, RunMigrationsError> {
+            migration_status_with_config(source, ALL_MIGRATIONS.to_vec(), config()).await
+        }
     }
 }
 
 fn migration_literals_from_path(path: &Path) -> Result<Vec<proc_macro2::TokenStream>, Box<dyn Error>> {
-    let mut migrations = migrations_shared::migration_paths_in_directory(path)?;
-
-    migrations.sort_by_key(DirEntry::path);
-
-    migrations
-        .into_iter()
-        .map(|e| migration_literal_from_path(&e.path()))
+    migration_files_in_directory(path)?
+        .iter()
+        .map(migration_literal_from_files)
         .collect()
 }
 
-fn migration_literal_from_path(path: &Path) -> Result<proc_macro2::TokenStream, Box<dyn Error>> {
-    let version = migrations_shared::version_from_path(path)?;
-    let sql_file = path.join("up.sql");
-    let sql_file_path = sql_file.to_str();
+fn migration_literal_from_files(files: &MigrationFiles) -> Result<proc_macro2::TokenStream, Box<dyn Error>> {
+    let version = files.version()?;
+    let (up_sql, down_sql) = match files {
+        MigrationFiles::Directory(path) => {
+            let down_sql = Some(path.join("down.sql")).filter(|path| path.is_file());
+            (
+                include_str_of(&path.join("up.sql")),
+                optional_include_str_of(down_sql.as_deref()),
+            )
+        }
+        MigrationFiles::SingleFile(path) => {
+            let sql = fs::read_to_string(path)?;
+            let (up, down) = split_up_down(&sql).ok_or_else(|| MigrationError::UnknownMigrationFormat(path.clone()))?;
+            let up_sql = up.sql;
+            // The sections are embedded as literals, the file is still included so that changing
+            // it rebuilds the crate
+            let file = include_str_of(path);
+            (
+                quote!({
+                    const _: &str = #file;
+                    #up_sql
+                }),
+                match down.map(|down| down.sql) {
+                    Some(down_sql) => quote!(Some(#down_sql)),
+                    None => quote!(None),
+                },
+            )
+        }
+        MigrationFiles::Flyway { up, undo } => (include_str_of(up), optional_include_str_of(undo.as_deref())),
+        MigrationFiles::Repeatable { script, .. } => (include_str_of(script), quote!(None)),
+    };
+    let repeatable = files.is_repeatable();
 
     Ok(quote!(&EmbeddedMigration {
         version: #version,
-        up_sql: include_str!(#sql_file_path),
+        up_sql: #up_sql,
+        down_sql: #down_sql,
+        repeatable: #repeatable,
     }))
 }
+
+fn include_str_of(path: &Path) -> proc_macro2::TokenStream {
+    let path = path.to_str();
+    quote!(include_str!(#path))
+}
+
+fn optional_include_str_of(path: Option<&Path>) -> proc_macro2::TokenStream {
+    match path {
+        Some(path) => {
+            let include_str = include_str_of(path);
+            quote!(Some(#include_str))
+        }
+        None => quote!(None),
+    }
+}
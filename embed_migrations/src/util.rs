@@ -37,10 +37,6 @@ pub fn get_options_from_input(
     }
 }
 
-pub fn get_option(options: &[Meta], option_name: &str, on_bug: fn() -> !) -> String {
-    get_optional_option(options, option_name).unwrap_or_else(|| on_bug())
-}
-
 pub fn get_optional_option(options: &[Meta], option_name: &str) -> Option<String> {
     options
         .iter()
@@ -1,41 +1,97 @@
+use chrono::Utc;
 use migrations_shared::async_trait;
+use migrations_shared::connection::ConnectionSource;
 use migrations_shared::error::*;
+use migrations_shared::files::*;
 use migrations_shared::migration::*;
-use migrations_shared::run_migrations;
+use migrations_shared::observer::*;
+use migrations_shared::run_migrations_with_config;
 use migrations_shared::runner::*;
-use std::fs::File;
-use std::io::{stdout, Read, Write};
+use migrations_shared::status::MigrationStatus;
+use std::fs::{self, File};
+use std::io::{stdout, Read};
 use std::path::{Path, PathBuf};
 
 /// Runs all migrations that have not yet been run. This function will print all progress to
 /// stdout. This function will return an `Err` if some error occurs reading the migrations, or if
 /// any migration fails to run. Each migration is run in its own transaction, so some migrations
-/// may be committed, even if a later migration fails to run.
+/// may be committed, even if a later migration fails to run. Set
+/// `MigrationsConfig::single_transaction` to apply them all atomically instead.
 ///
 /// It should be noted that this runs all migrations that have not already been run, regardless of
 /// whether or not their version is later than the latest run migration. This is generally not a
 /// problem, and eases the more common case of two developers generating independent migrations on
 /// a branch. Whoever created the second one will eventually need to run the first when both
-/// branches are merged.
-pub async fn run_pending_migrations(pool: DbConnectionPool) -> Result<(), Error> {
+/// branches are merged. Use `MigrationsConfig::out_of_order` to warn about or reject such
+/// migrations instead.
+pub async fn run_pending_migrations<S: ConnectionSource>(source: S) -> Result<(), Error> {
+    run_pending_migrations_with_config(source, MigrationsConfig::default()).await
+}
+
+/// Same as `run_pending_migrations`, using the given runner configuration.
+pub async fn run_pending_migrations_with_config<S: ConnectionSource>(
+    source: S,
+    config: MigrationsConfig,
+) -> Result<(), Error> {
     let migrations_dir = migrations_shared::find_migrations_directory()?;
-    run_pending_migrations_in_directory(pool, &migrations_dir, &mut stdout()).await
+    run_pending_migrations_in_directory(source, &migrations_dir, config, &WriteObserver::new(stdout())).await
 }
 
 #[doc(hidden)]
-pub async fn run_pending_migrations_in_directory(
-    pool: DbConnectionPool,
+pub async fn run_pending_migrations_in_directory<S: ConnectionSource>(
+    source: S,
     migrations_dir: &Path,
-    output: &mut (dyn Write + Send + Sync),
+    config: MigrationsConfig,
+    observer: &(dyn MigrationObserver + Sync),
 ) -> Result<(), Error> {
     let all_migrations = migrations_in_directory(migrations_dir)?;
-    run_migrations(pool, all_migrations, output).await
+    run_migrations_with_config(source, all_migrations, config, observer).await
+}
+
+/// Compares the migrations in the given directory with the migrations recorded in the database.
+pub async fn migration_status_in_directory<S: ConnectionSource>(
+    source: S,
+    migrations_dir: &Path,
+) -> Result<MigrationStatus, Error> {
+    let all_migrations = migrations_in_directory(migrations_dir)?;
+    migrations_shared::migration_status(source, all_migrations).await
+}
+
+/// Creates a new migration directory named `<timestamp>_<name>` in `migrations_dir`, with empty
+/// `up.sql` and `down.sql` files, and returns its path. The timestamp is the current UTC time.
+/// Returns an `Err` if the name is invalid, or if a migration with the same version already exists.
+pub fn generate_migration(migrations_dir: &Path, name: &str) -> Result<PathBuf, Error> {
+    let valid_name = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid_name {
+        return Err(Error::InvalidMigrationName(name.to_string()));
+    }
+
+    let version = Utc::now().format("%Y%m%d%H%M%S").to_string();
+    if migrations_dir.is_dir() {
+        for entry in migrations_shared::migration_paths_in_directory(migrations_dir)? {
+            if migrations_shared::version_from_path(&entry.path())? == version {
+                return Err(Error::MigrationVersionExists(version));
+            }
+        }
+    }
+
+    let path = migrations_dir.join(format!("{}_{}", version, name));
+    fs::create_dir_all(&path)?;
+    File::create(path.join("up.sql"))?;
+    File::create(path.join("down.sql"))?;
+    Ok(path)
 }
 
-fn migrations_in_directory(path: &Path) -> Result<Vec<Box<dyn Migration + Send + Sync>>, Error> {
-    migrations_shared::migration_paths_in_directory(path)?
-        .iter()
-        .map(|e| migration_from(e.path()))
+/// Loads every migration in the given directory: `<timestamp>_<name>` directories containing
+/// `up.sql` and `down.sql`, `<timestamp>_<name>.sql` files with `-- +up` and `-- +down` sections,
+/// and pairs of Flyway-style `V<version>__<name>.sql` and `U<version>__<name>.sql` files, followed
+/// by the repeatable migrations: `R_<name>` directories containing `up.sql`, `R__<name>.sql` files
+/// and the `.sql` files of the `repeatable` directory. Returns an `Err` if two migrations have the
+/// same version.
+pub fn migrations_in_directory(path: &Path) -> Result<Vec<Box<dyn Migration + Send + Sync>>, Error> {
+    migration_files_in_directory(path)?
+        .into_iter()
+        .map(migration_from_files)
         .collect()
 }
 
@@ -48,9 +104,27 @@ pub fn migration_from(path: PathBuf) -> Result<Box<dyn Migration + Send + Sync>,
     }
 }
 
+fn migration_from_files(files: MigrationFiles) -> Result<Box<dyn Migration + Send + Sync>, Error> {
+    let version = files.version()?;
+    match files {
+        MigrationFiles::Directory(path) => migration_from(path),
+        MigrationFiles::SingleFile(path) => {
+            let migration = SingleFileMigration(path, version);
+            migration.sections()?;
+            Ok(Box::new(migration))
+        }
+        MigrationFiles::Flyway { up, undo } => Ok(Box::new(FlywayMigration { up, undo, version })),
+        MigrationFiles::Repeatable { path, script } => Ok(Box::new(RepeatableMigration {
+            path,
+            script,
+            name: version,
+        })),
+    }
+}
+
 fn valid_sql_migration_directory(path: &Path) -> bool {
     file_names(path)
-        .map(|files| files.contains(&"down.sql".into()) && files.contains(&"up.sql".into()))
+        .map(|files| files.contains(&"up.sql".into()))
         .unwrap_or(false)
 }
 
@@ -76,6 +150,13 @@ fn file_names(path: &Path) -> Result<Vec<String>, Error> {
 
 pub struct SqlFileMigration(pub PathBuf, pub String);
 
+impl SqlFileMigration {
+    /// The `down.sql` script, if the migration has one
+    fn down_path(&self) -> Option<PathBuf> {
+        Some(self.0.join("down.sql")).filter(|path| path.is_file())
+    }
+}
+
 #[async_trait]
 impl Migration for SqlFileMigration {
     fn version(&self) -> &str {
@@ -87,21 +168,269 @@ impl Migration for SqlFileMigration {
     }
 
     async fn revert(&self, transaction: &DbTransaction<'_>) -> Result<(), Error> {
-        run_sql_from_file(transaction, &self.0.join("down.sql")).await
+        match self.down_path() {
+            Some(path) => run_sql_from_file(transaction, &path).await,
+            None => Err(Error::IrreversibleMigration(self.1.clone())),
+        }
     }
 
     fn file_path(&self) -> Option<&Path> {
         Some(self.0.as_path())
     }
+
+    fn up_sql(&self) -> Result<Option<String>, Error> {
+        Ok(Some(read_sql_file(&self.0.join("up.sql"))?))
+    }
+
+    fn down_sql(&self) -> Result<Option<String>, Error> {
+        self.down_path().map(|path| read_sql_file(&path)).transpose()
+    }
+
+    fn irreversible(&self) -> Result<bool, Error> {
+        irreversible(self.down_path().is_none(), self)
+    }
 }
 
-async fn run_sql_from_file(transaction: &DbTransaction<'_>, path: &Path) -> Result<(), Error> {
+/// A `<timestamp>_<name>.sql` file containing both scripts of a migration, in `-- +up` and
+/// `-- +down` sections
+pub struct SingleFileMigration(pub PathBuf, pub String);
+
+impl SingleFileMigration {
+    fn sections(&self) -> Result<(String, Option<String>), Error> {
+        let sql = read_sql_file(&self.0)?;
+        match split_up_down(&sql) {
+            Some((up, down)) => Ok((up.sql.to_string(), down.map(|down| down.sql.to_string()))),
+            None => Err(Error::UnknownMigrationFormat(self.0.clone())),
+        }
+    }
+}
+
+#[async_trait]
+impl Migration for SingleFileMigration {
+    fn version(&self) -> &str {
+        &self.1
+    }
+
+    async fn run(&self, transaction: &DbTransaction<'_>) -> Result<(), Error> {
+        run_sql(transaction, &self.sections()?.0).await
+    }
+
+    async fn revert(&self, transaction: &DbTransaction<'_>) -> Result<(), Error> {
+        match self.sections()?.1 {
+            Some(sql) => run_sql(transaction, &sql).await,
+            None => Err(Error::IrreversibleMigration(self.1.clone())),
+        }
+    }
+
+    fn file_path(&self) -> Option<&Path> {
+        Some(self.0.as_path())
+    }
+
+    fn script_path(&self, _sql_file: &str) -> Option<PathBuf> {
+        Some(self.0.clone())
+    }
+
+    fn script_line_offset(&self, sql_file: &str) -> Result<usize, Error> {
+        let sql = read_sql_file(&self.0)?;
+        let (up, down) = split_up_down(&sql).ok_or_else(|| Error::UnknownMigrationFormat(self.0.clone()))?;
+        let section = match down {
+            Some(down) if sql_file == "down.sql" => down,
+            _ => up,
+        };
+        Ok(sql[..section.offset].matches('\n').count())
+    }
+
+    fn up_sql(&self) -> Result<Option<String>, Error> {
+        Ok(Some(self.sections()?.0))
+    }
+
+    fn down_sql(&self) -> Result<Option<String>, Error> {
+        Ok(self.sections()?.1)
+    }
+
+    fn irreversible(&self) -> Result<bool, Error> {
+        irreversible(self.sections()?.1.is_none(), self)
+    }
+}
+
+/// A Flyway-style `V<version>__<name>.sql` file and the matching `U<version>__<name>.sql` file,
+/// if the migration has one
+pub struct FlywayMigration {
+    pub up: PathBuf,
+    pub undo: Option<PathBuf>,
+    pub version: String,
+}
+
+#[async_trait]
+impl Migration for FlywayMigration {
+    fn version(&self) -> &str {
+        &self.version
+    }
+
+    async fn run(&self, transaction: &DbTransaction<'_>) -> Result<(), Error> {
+        run_sql_from_file(transaction, &self.up).await
+    }
+
+    async fn revert(&self, transaction: &DbTransaction<'_>) -> Result<(), Error> {
+        match &self.undo {
+            Some(undo) => run_sql_from_file(transaction, undo).await,
+            None => Err(Error::IrreversibleMigration(self.version.clone())),
+        }
+    }
+
+    fn file_path(&self) -> Option<&Path> {
+        Some(self.up.as_path())
+    }
+
+    fn script_path(&self, sql_file: &str) -> Option<PathBuf> {
+        match sql_file {
+            "down.sql" => self.undo.clone(),
+            _ => Some(self.up.clone()),
+        }
+    }
+
+    fn up_sql(&self) -> Result<Option<String>, Error> {
+        Ok(Some(read_sql_file(&self.up)?))
+    }
+
+    fn down_sql(&self) -> Result<Option<String>, Error> {
+        self.undo.as_deref().map(read_sql_file).transpose()
+    }
+
+    fn irreversible(&self) -> Result<bool, Error> {
+        irreversible(self.undo.is_none(), self)
+    }
+}
+
+/// A repeatable migration, re-applied whenever its script changes. `path` is its `R_<name>`
+/// directory or its file, `script` its SQL file.
+pub struct RepeatableMigration {
+    pub path: PathBuf,
+    pub script: PathBuf,
+    pub name: String,
+}
+
+#[async_trait]
+impl Migration for RepeatableMigration {
+    fn version(&self) -> &str {
+        &self.name
+    }
+
+    async fn run(&self, transaction: &DbTransaction<'_>) -> Result<(), Error> {
+        run_sql_from_file(transaction, &self.script).await
+    }
+
+    async fn revert(&self, _transaction: &DbTransaction<'_>) -> Result<(), Error> {
+        Err(Error::IrreversibleMigration(self.name.clone()))
+    }
+
+    fn file_path(&self) -> Option<&Path> {
+        Some(self.path.as_path())
+    }
+
+    fn script_path(&self, _sql_file: &str) -> Option<PathBuf> {
+        Some(self.script.clone())
+    }
+
+    fn up_sql(&self) -> Result<Option<String>, Error> {
+        Ok(Some(read_sql_file(&self.script)?))
+    }
+
+    fn irreversible(&self) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn repeatable(&self) -> bool {
+        true
+    }
+}
+
+/// A file migration is irreversible if it has no down script, or if its up script is marked with
+/// `IRREVERSIBLE_HEADER`
+fn irreversible(missing_down_sql: bool, migration: &dyn Migration) -> Result<bool, Error> {
+    Ok(missing_down_sql || migration.up_sql()?.is_some_and(|sql| has_irreversible_header(&sql)))
+}
+
+fn read_sql_file(path: &Path) -> Result<String, Error> {
     let mut sql = String::new();
     let mut file = File::open(path)?;
     file.read_to_string(&mut sql)?;
+    Ok(sql)
+}
+
+async fn run_sql_from_file(transaction: &DbTransaction<'_>, path: &Path) -> Result<(), Error> {
+    run_sql(transaction, &read_sql_file(path)?).await
+}
+
+async fn run_sql(transaction: &DbTransaction<'_>, sql: &str) -> Result<(), Error> {
     if sql.is_empty() {
         return Err(Error::EmptyMigration);
     }
-    transaction.batch_execute(&sql).await?;
+    transaction.batch_execute(sql).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A migrations directory under the system temp directory, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("migrations_generate_{}_{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn generate_migration_creates_both_scripts() {
+        let dir = TempDir::new("created");
+        let path = generate_migration(&dir.0, "add_users-2").unwrap();
+        assert_eq!(path.parent(), Some(dir.0.as_path()));
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        let (version, name) = file_name.split_at(14);
+        assert!(version.chars().all(|c| c.is_ascii_digit()), "{}", file_name);
+        assert_eq!(name, "_add_users-2");
+        for script in &["up.sql", "down.sql"] {
+            assert_eq!(fs::read_to_string(path.join(script)).unwrap(), "");
+        }
+        assert_eq!(migrations_in_directory(&dir.0).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn generate_migration_rejects_invalid_names() {
+        let dir = TempDir::new("invalid");
+        for name in &["", "add users", "../users", "users.sql", "utilisé"] {
+            match generate_migration(&dir.0, name) {
+                Err(Error::InvalidMigrationName(invalid)) => assert_eq!(&invalid, name),
+                other => panic!("unexpected result for {:?}: {:?}", name, other),
+            }
+        }
+        assert!(!dir.0.exists());
+    }
+
+    #[test]
+    fn generate_migration_rejects_existing_versions() {
+        let dir = TempDir::new("existing");
+        // Cover the next few seconds, so the generated version is taken whenever the test runs
+        let now = Utc::now();
+        for seconds in 0..3 {
+            let version = (now + chrono::Duration::seconds(seconds)).format("%Y-%m-%d-%H%M%S");
+            fs::create_dir_all(dir.0.join(format!("{}_existing", version))).unwrap();
+        }
+        match generate_migration(&dir.0, "users") {
+            Err(Error::MigrationVersionExists(version)) => assert_eq!(version.len(), 14),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 3);
+    }
+}
@@ -0,0 +1,107 @@
+//! TLS connector for the connection pool, backed by `native-tls`
+
+use std::future::Future;
+use std::io;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_postgres::tls::{self, ChannelBinding};
+
+/// Creates TLS connections to the database. Whether they are used is decided by the `sslmode`
+/// of the connection string: with the default `prefer`, servers without TLS are still reachable.
+#[derive(Clone)]
+pub struct MakeTlsConnector(tokio_tls::TlsConnector);
+
+impl MakeTlsConnector {
+    /// Connector verifying the server certificate and host name against the system's trusted
+    /// certificates if `verify` is set. Otherwise connections are encrypted without verifying the
+    /// server, like libpq does in its `prefer` and `require` modes.
+    pub fn new(verify: bool) -> Result<MakeTlsConnector, native_tls::Error> {
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(!verify)
+            .danger_accept_invalid_hostnames(!verify)
+            .build()?;
+        Ok(MakeTlsConnector(connector.into()))
+    }
+}
+
+impl<S> tls::MakeTlsConnect<S> for MakeTlsConnector
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = TlsStream<S>;
+    type TlsConnect = TlsConnector;
+    type Error = native_tls::Error;
+
+    fn make_tls_connect(&mut self, domain: &str) -> Result<TlsConnector, native_tls::Error> {
+        Ok(TlsConnector {
+            connector: self.0.clone(),
+            domain: domain.to_string(),
+        })
+    }
+}
+
+pub struct TlsConnector {
+    connector: tokio_tls::TlsConnector,
+    domain: String,
+}
+
+impl<S> tls::TlsConnect<S> for TlsConnector
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = TlsStream<S>;
+    type Error = native_tls::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<TlsStream<S>, native_tls::Error>> + Send>>;
+
+    fn connect(self, stream: S) -> Self::Future {
+        Box::pin(async move {
+            let stream = self.connector.connect(&self.domain, stream).await?;
+            Ok(TlsStream(stream))
+        })
+    }
+}
+
+pub struct TlsStream<S>(tokio_tls::TlsStream<S>);
+
+impl<S> AsyncRead for TlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [MaybeUninit<u8>]) -> bool {
+        self.0.prepare_uninitialized_buffer(buf)
+    }
+
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for TlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl<S> tls::TlsStream for TlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // tokio-tls does not expose the server certificate needed for channel binding
+    fn channel_binding(&self) -> ChannelBinding {
+        ChannelBinding::none()
+    }
+}
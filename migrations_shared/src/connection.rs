@@ -0,0 +1,67 @@
+use crate::error::*;
+use crate::runner::*;
+use async_trait::async_trait;
+use std::ops::DerefMut;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+
+/// Connection handed out by a `ConnectionSource`, e.g. a pooled connection or a locked client
+pub type DbConnection<'a> = Box<dyn DerefMut<Target = Client> + Send + 'a>;
+
+/// Something `MigrationsRunner` can get database connections from. Implemented for bb8 pools,
+/// for a single `tokio_postgres::Client` behind a `tokio::sync::Mutex`, and for references to
+/// and `Arc`s of either.
+#[async_trait]
+pub trait ConnectionSource: Send + Sync {
+    /// Get a connection to run queries and migrations on
+    async fn connection(&self) -> Result<DbConnection<'_>, Error>;
+
+    /// Get a connection, separate from the ones returned by `connection`, to hold the migrations
+    /// advisory lock on. Returns `None` when every connection belongs to the same session, in
+    /// which case the lock is taken on that session.
+    async fn lock_connection(&self) -> Result<Option<DbConnection<'_>>, Error> {
+        Ok(None)
+    }
+}
+
+#[async_trait]
+impl<Tls: MakeTls> ConnectionSource for DbConnectionPool<Tls> {
+    async fn connection(&self) -> Result<DbConnection<'_>, Error> {
+        Ok(Box::new(self.get().await?))
+    }
+
+    async fn lock_connection(&self) -> Result<Option<DbConnection<'_>>, Error> {
+        let client = self.dedicated_connection().await?;
+        Ok(Some(Box::new(Box::new(client))))
+    }
+}
+
+#[async_trait]
+impl ConnectionSource for Mutex<Client> {
+    async fn connection(&self) -> Result<DbConnection<'_>, Error> {
+        Ok(Box::new(self.lock().await))
+    }
+}
+
+#[async_trait]
+impl<S: ConnectionSource + ?Sized> ConnectionSource for &S {
+    async fn connection(&self) -> Result<DbConnection<'_>, Error> {
+        (**self).connection().await
+    }
+
+    async fn lock_connection(&self) -> Result<Option<DbConnection<'_>>, Error> {
+        (**self).lock_connection().await
+    }
+}
+
+#[async_trait]
+impl<S: ConnectionSource + ?Sized> ConnectionSource for Arc<S> {
+    async fn connection(&self) -> Result<DbConnection<'_>, Error> {
+        (**self).connection().await
+    }
+
+    async fn lock_connection(&self) -> Result<Option<DbConnection<'_>>, Error> {
+        (**self).lock_connection().await
+    }
+}
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::io;
 use std::path::PathBuf;
 use thiserror::*;
@@ -7,16 +8,58 @@ pub enum Error {
     #[error("Unable to find migrations directory in {0:?} or any parent directories.")]
     MigrationDirectoryNotFound(PathBuf),
     #[error(
-        "Invalid migration directory, the directory's name should be \
-        <timestamp>_<name_of_migration>, and it should only contain up.sql and down.sql."
+        "Invalid migration {0:?}, it should be a <timestamp>_<name_of_migration> directory \
+        containing up.sql and down.sql, a <timestamp>_<name_of_migration>.sql file with `-- +up` \
+        and `-- +down` sections, or a pair of Flyway-style V<version>__<name>.sql and \
+        U<version>__<name>.sql files. Flyway versions are recorded with each part zero-padded to \
+        14 digits, e.g. V1.2 as 00000000000001.00000000000002, so they sort before timestamps. \
+        The down script may be left out for irreversible migrations. Repeatable migrations are \
+        R_<name> directories containing up.sql, R__<name>.sql files, or .sql files in the \
+        repeatable directory."
     )]
     UnknownMigrationFormat(PathBuf),
-    #[error("Unable to find migration version to revert in the migrations directory.")]
+    #[error("Unable to find migration version {0} in the migrations directory.")]
     UnknownMigrationVersion(String),
     #[error("No migrations have been run. Did you forget `migration run`?")]
     NoMigrationRun,
+    #[error(
+        "Invalid migration name `{0}`, it should only contain ASCII letters, digits, \
+        underscores and dashes."
+    )]
+    InvalidMigrationName(String),
+    #[error("Migrations {1:?} and {2:?} have the same version {0}.")]
+    DuplicateMigrationVersion(String, PathBuf, PathBuf),
+    #[error(
+        "Migration {1:?} has version or name {0}, which is longer than {} characters.",
+        crate::runner::MAX_VERSION_LENGTH
+    )]
+    MigrationVersionTooLong(String, PathBuf),
+    #[error("A migration with version {0} already exists.")]
+    MigrationVersionExists(String),
     #[error("Failed with: Attempted to run an empty migration.")]
     EmptyMigration,
+    #[error("Unable to acquire migrations lock {0}, another process is running migrations.")]
+    LockUnavailable(i64),
+    #[error(
+        "Already applied migrations have been modified: {}. Restore the original scripts or \
+        enable `repair_checksums` (`--repair-checksums` on the command line) to accept the changes.",
+        .0.join(", ")
+    )]
+    ChecksumMismatch(Vec<String>),
+    #[error(
+        "Migration {0} must run outside of a transaction, it can't be applied in single-transaction \
+        mode or redone."
+    )]
+    NoTransactionMigration(String),
+    #[error("Migration {0} is irreversible, it has no down script or is marked with `-- migrations:irreversible`.")]
+    IrreversibleMigration(String),
+    #[error(
+        "Pending migrations {} are older than the latest applied migration.",
+        .0.join(", ")
+    )]
+    OutOfOrderMigrations(Vec<String>),
+    #[error(transparent)]
+    StatementFailed(Box<StatementError>),
 
     #[error(transparent)]
     IoError(#[from] io::Error),
@@ -25,3 +68,43 @@ pub enum Error {
     #[error(transparent)]
     Bb8(#[from] bb8_postgres::bb8::RunError<tokio_postgres::Error>),
 }
+
+/// A statement of a migration script rejected by the database
+#[derive(Debug, Error)]
+pub struct StatementError {
+    /// Name of the migration
+    pub migration: String,
+    /// Path of the script, or `<version>/<file>` for migrations without a directory
+    pub script: String,
+    /// The failing statement, if the database reported where the error occurred
+    pub statement: Option<String>,
+    /// Line of the error in the script, starting at 1
+    pub line: Option<usize>,
+    /// Column of the error in its line, starting at 1
+    pub column: Option<usize>,
+    pub message: String,
+    pub detail: Option<String>,
+    pub hint: Option<String>,
+    #[source]
+    pub source: tokio_postgres::Error,
+}
+
+impl fmt::Display for StatementError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Migration {} failed in {}", self.migration, self.script)?;
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, ":{}:{}", line, column)?;
+        }
+        write!(f, ": {}", self.message)?;
+        if let Some(statement) = &self.statement {
+            write!(f, "\nStatement: {}", statement)?;
+        }
+        if let Some(detail) = &self.detail {
+            write!(f, "\nDetail: {}", detail)?;
+        }
+        if let Some(hint) = &self.hint {
+            write!(f, "\nHint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
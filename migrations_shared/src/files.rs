@@ -0,0 +1,444 @@
+use crate::error::*;
+use crate::runner::MAX_VERSION_LENGTH;
+use crate::{migration_paths_in_directory, version_from_path};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Line starting the up section of a single-file migration
+pub const UP_MARKER: &str = "-- +up";
+/// Line starting the down section of a single-file migration
+pub const DOWN_MARKER: &str = "-- +down";
+
+/// Subdirectory of the migrations directory whose `.sql` files are repeatable migrations
+pub const REPEATABLE_DIRECTORY: &str = "repeatable";
+/// Prefix of repeatable migration directories and files, e.g. `R_views` or `R__views.sql`
+pub const REPEATABLE_PREFIX: &str = "R_";
+
+/// Width Flyway version parts are zero-padded to, so that versions sort numerically
+const FLYWAY_VERSION_PART_WIDTH: usize = 14;
+/// Number of parts of the longest Flyway version fitting in the `version` column
+const FLYWAY_MAX_VERSION_PARTS: usize = (MAX_VERSION_LENGTH + 1) / (FLYWAY_VERSION_PART_WIDTH + 1);
+
+/// The files of a migration in a migrations directory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MigrationFiles {
+    /// A `<timestamp>_<name>` directory containing `up.sql` and, unless the migration is
+    /// irreversible, `down.sql`
+    Directory(PathBuf),
+    /// A `<timestamp>_<name>.sql` file with a `-- +up` section and, unless the migration is
+    /// irreversible, a `-- +down` section
+    SingleFile(PathBuf),
+    /// A Flyway-style `V<version>__<name>.sql` file and, unless the migration is irreversible, the
+    /// matching `U<version>__<name>.sql` file.
+    ///
+    /// The version recorded in the migrations table differs from the one written in the file
+    /// name: each part is zero-padded to 14 digits, so that versions sort numerically, e.g.
+    /// `V1.2__users.sql` is recorded as `00000000000001.00000000000002`. Flyway versions thus
+    /// sort before `<timestamp>_<name>` migrations, unless their first part is a timestamp
+    /// itself, and don't match the versions of Flyway's own history table.
+    Flyway { up: PathBuf, undo: Option<PathBuf> },
+    /// A repeatable migration: an `R_<name>` directory containing `up.sql`, an `R__<name>.sql`
+    /// file, or a `<name>.sql` file in the `repeatable` directory. `script` is its SQL file.
+    Repeatable { path: PathBuf, script: PathBuf },
+}
+
+impl MigrationFiles {
+    /// The directory or file identifying the migration, its name is the migration's name
+    pub fn path(&self) -> &Path {
+        match self {
+            MigrationFiles::Directory(path) | MigrationFiles::SingleFile(path) => path,
+            MigrationFiles::Flyway { up, .. } => up,
+            MigrationFiles::Repeatable { path, .. } => path,
+        }
+    }
+
+    /// The version of the migration, or the name of a repeatable migration
+    pub fn version(&self) -> Result<String, Error> {
+        match self {
+            MigrationFiles::Repeatable { path, .. } => Ok(repeatable_name(path)),
+            _ => version_from_path(self.path()),
+        }
+    }
+
+    pub fn is_repeatable(&self) -> bool {
+        matches!(self, MigrationFiles::Repeatable { .. })
+    }
+}
+
+/// Lists the migrations in the given directory, sorted by version, followed by the repeatable
+/// migrations sorted by name. Returns `Error::DuplicateMigrationVersion` if two migrations have
+/// the same version or name, `Error::MigrationVersionTooLong` if a version or name doesn't fit in
+/// the migrations table, and `Error::UnknownMigrationFormat` for files which are not part of a
+/// migration.
+pub fn migration_files_in_directory(path: &Path) -> Result<Vec<MigrationFiles>, Error> {
+    let mut migrations = Vec::new();
+    let mut flyway_up = Vec::new();
+    let mut flyway_undo = HashMap::new();
+    for entry in migration_paths_in_directory(path)? {
+        let path = entry.path();
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if path.is_dir() && file_name == REPEATABLE_DIRECTORY {
+            for entry in migration_paths_in_directory(&path)? {
+                let path = entry.path();
+                if path.is_dir() || !entry.file_name().to_string_lossy().ends_with(".sql") {
+                    return Err(Error::UnknownMigrationFormat(path));
+                }
+                migrations.push(MigrationFiles::Repeatable {
+                    script: path.clone(),
+                    path,
+                });
+            }
+        } else if path.is_dir() && file_name.starts_with(REPEATABLE_PREFIX) {
+            let script = path.join("up.sql");
+            if !script.is_file() {
+                return Err(Error::UnknownMigrationFormat(path));
+            }
+            migrations.push(MigrationFiles::Repeatable { path, script });
+        } else if path.is_dir() {
+            migrations.push(MigrationFiles::Directory(path));
+        } else if !file_name.ends_with(".sql") {
+            return Err(Error::UnknownMigrationFormat(path));
+        } else if file_name.starts_with(REPEATABLE_PREFIX) {
+            migrations.push(MigrationFiles::Repeatable {
+                script: path.clone(),
+                path,
+            });
+        } else if let Some((prefix, version)) = flyway_version(&file_name) {
+            match prefix {
+                'V' => flyway_up.push((version, path)),
+                _ => {
+                    flyway_undo.insert(version, path);
+                }
+            }
+        } else {
+            migrations.push(MigrationFiles::SingleFile(path));
+        }
+    }
+    for (version, up) in flyway_up {
+        let undo = flyway_undo.remove(&version);
+        migrations.push(MigrationFiles::Flyway { up, undo });
+    }
+    if let Some(undo) = flyway_undo.into_values().min() {
+        return Err(Error::UnknownMigrationFormat(undo));
+    }
+
+    let mut versioned = migrations
+        .into_iter()
+        .map(|m| match m.version()? {
+            version if version.chars().count() > MAX_VERSION_LENGTH => {
+                Err(Error::MigrationVersionTooLong(version, m.path().to_path_buf()))
+            }
+            version => Ok((version, m)),
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    versioned.sort_by(|(a_version, a), (b_version, b)| {
+        (a.is_repeatable(), a_version, a.path()).cmp(&(b.is_repeatable(), b_version, b.path()))
+    });
+    let mut paths: HashMap<&str, &Path> = HashMap::new();
+    for (version, migration) in &versioned {
+        if let Some(other) = paths.insert(version, migration.path()) {
+            return Err(Error::DuplicateMigrationVersion(
+                version.clone(),
+                other.to_path_buf(),
+                migration.path().to_path_buf(),
+            ));
+        }
+    }
+    Ok(versioned.into_iter().map(|(_, m)| m).collect())
+}
+
+/// The name of a repeatable migration: its file name, without `REPEATABLE_PREFIX` and `.sql`
+fn repeatable_name(path: &Path) -> String {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let name = file_name.strip_suffix(".sql").unwrap_or(&file_name);
+    let name = name
+        .strip_prefix(REPEATABLE_PREFIX)
+        .map_or(name, |name| name.trim_start_matches('_'));
+    name.to_string()
+}
+
+/// Returns the `V` or `U` prefix and the normalized version of a Flyway-style file name such as
+/// `V1_2__add_users.sql`, see `flyway_version_number`.
+pub fn flyway_version(file_name: &str) -> Option<(char, String)> {
+    let prefix = file_name.chars().next().filter(|c| *c == 'V' || *c == 'U')?;
+    let separator = file_name.find("__")?;
+    Some((prefix, flyway_version_number(&file_name[1..separator])?))
+}
+
+/// Normalizes a Flyway version such as `1.2` or `1_2`, as written in file names, to the version
+/// recorded in the migrations table. Each part is zero-padded so versions sort numerically.
+/// Returns `None` if the version has more parts or longer parts than the recorded version can hold.
+pub fn flyway_version_number(version: &str) -> Option<String> {
+    let parts: Vec<_> = version.split(['.', '_']).collect();
+    let valid_part = |part: &&str| {
+        !part.is_empty() && part.len() <= FLYWAY_VERSION_PART_WIDTH && part.chars().all(|c| c.is_ascii_digit())
+    };
+    if parts.len() > FLYWAY_MAX_VERSION_PARTS || !parts.iter().all(valid_part) {
+        return None;
+    }
+    let parts: Vec<_> = parts
+        .iter()
+        .map(|part| format!("{:0>width$}", part, width = FLYWAY_VERSION_PART_WIDTH))
+        .collect();
+    Some(parts.join("."))
+}
+
+/// Splits a single-file migration into its up and down sections. Returns `None` if it does not
+/// contain an `UP_MARKER` line, and no down section if there is no `DOWN_MARKER` line after it. A
+/// section containing only whitespace is returned as an empty string, so that running it fails
+/// with `Error::EmptyMigration`.
+pub fn split_up_down(sql: &str) -> Option<(Section<'_>, Option<Section<'_>>)> {
+    let (_, up_start) = find_marker(sql, UP_MARKER, 0)?;
+    match find_marker(sql, DOWN_MARKER, up_start) {
+        Some((up_end, down_start)) => Some((
+            section(sql, up_start, up_end),
+            Some(section(sql, down_start, sql.len())),
+        )),
+        None => Some((section(sql, up_start, sql.len()), None)),
+    }
+}
+
+/// A section of a single-file migration
+#[derive(Debug, PartialEq)]
+pub struct Section<'a> {
+    pub sql: &'a str,
+    /// Byte offset of the section in the file, always at the start of a line
+    pub offset: usize,
+}
+
+fn section(sql: &str, start: usize, end: usize) -> Section<'_> {
+    let section = &sql[start..end];
+    Section {
+        sql: if section.trim().is_empty() { "" } else { section },
+        offset: start,
+    }
+}
+
+/// Returns the offsets of the first `marker` line at or after `from`, and of the line following it
+fn find_marker(sql: &str, marker: &str, from: usize) -> Option<(usize, usize)> {
+    let mut line_start = from;
+    while line_start < sql.len() {
+        let line_end = sql[line_start..].find('\n').map_or(sql.len(), |i| line_start + i);
+        let next_line = (line_end + 1).min(sql.len());
+        if sql[line_start..line_end].trim().eq_ignore_ascii_case(marker) {
+            return Some((line_start, next_line));
+        }
+        line_start = next_line;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A migrations directory under the system temp directory, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str, files: &[&str]) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("migrations_files_{}_{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            for file in files {
+                let path = dir.join(file);
+                if file.ends_with('/') {
+                    fs::create_dir_all(&path).unwrap();
+                } else {
+                    fs::create_dir_all(path.parent().unwrap()).unwrap();
+                    fs::write(&path, "SELECT 1;").unwrap();
+                }
+            }
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn padded(parts: &[&str]) -> String {
+        let parts: Vec<_> = parts.iter().map(|part| format!("{:0>14}", part)).collect();
+        parts.join(".")
+    }
+
+    #[test]
+    fn flyway_version_of_file_names() {
+        let cases = [
+            ("V1__init.sql", Some(('V', padded(&["1"])))),
+            ("U1__init.sql", Some(('U', padded(&["1"])))),
+            ("V1.2__users.sql", Some(('V', padded(&["1", "2"])))),
+            ("V1_2__users.sql", Some(('V', padded(&["1", "2"])))),
+            ("V12345678901234__big.sql", Some(('V', padded(&["12345678901234"])))),
+            ("V123456789012345__too_big.sql", None),
+            ("V1.__users.sql", None),
+            ("Vx__users.sql", None),
+            ("V__users.sql", None),
+            ("V1_users.sql", None),
+            ("R__views.sql", None),
+            ("20200101000000_users.sql", None),
+        ];
+        for (file_name, expected) in cases {
+            assert_eq!(flyway_version(file_name), expected, "{}", file_name);
+        }
+    }
+
+    #[test]
+    fn flyway_version_fits_version_column() {
+        let longest = vec!["1"; FLYWAY_MAX_VERSION_PARTS].join(".");
+        let version = flyway_version_number(&longest).unwrap();
+        assert!(version.len() <= MAX_VERSION_LENGTH);
+        assert_eq!(flyway_version_number(&format!("{}.1", longest)), None);
+    }
+
+    #[test]
+    fn split_up_down_sections() {
+        let sql = "-- header\n-- +up\nCREATE TABLE a (id INT);\n  -- +DOWN  \nDROP TABLE a;\n";
+        let (up, down) = split_up_down(sql).unwrap();
+        assert_eq!(
+            up,
+            Section {
+                sql: "CREATE TABLE a (id INT);\n",
+                offset: 17
+            }
+        );
+        assert_eq!(
+            down,
+            Some(Section {
+                sql: "DROP TABLE a;\n",
+                offset: 55
+            })
+        );
+
+        let (up, down) = split_up_down("-- +up\nSELECT 1;").unwrap();
+        assert_eq!(
+            up,
+            Section {
+                sql: "SELECT 1;",
+                offset: 7
+            }
+        );
+        assert_eq!(down, None);
+
+        // Whitespace-only sections are empty, so that running them fails
+        let (up, down) = split_up_down("-- +up\n \n-- +down\n\n").unwrap();
+        assert_eq!(up, Section { sql: "", offset: 7 });
+        assert_eq!(down, Some(Section { sql: "", offset: 18 }));
+
+        // A down marker before the up marker doesn't end the up section
+        let (up, down) = split_up_down("-- +down\n-- +up\nSELECT 1;\n").unwrap();
+        assert_eq!(up.sql, "SELECT 1;\n");
+        assert_eq!(down, None);
+
+        assert_eq!(split_up_down("SELECT 1;\n-- +down\nSELECT 2;\n"), None);
+    }
+
+    #[test]
+    fn find_marker_matches_whole_lines() {
+        let sql = "SELECT '-- +up';\n-- +up extra\n  -- +Up\r\nSELECT 1;";
+        assert_eq!(find_marker(sql, UP_MARKER, 0), Some((30, 40)));
+        assert_eq!(find_marker(sql, UP_MARKER, 40), None);
+        assert_eq!(find_marker("-- +up", UP_MARKER, 0), Some((0, 6)));
+        assert_eq!(find_marker("", UP_MARKER, 0), None);
+    }
+
+    #[test]
+    fn migration_files_are_classified_and_sorted() {
+        let dir = TempDir::new(
+            "classified",
+            &[
+                "2020-01-02-000000_users/up.sql",
+                "2020-01-02-000000_users/down.sql",
+                "20200101000000_init.sql",
+                "V3__orders.sql",
+                "U3__orders.sql",
+                "V2.1__audit.sql",
+                "R__views.sql",
+                "R_functions/up.sql",
+                "repeatable/triggers.sql",
+            ],
+        );
+        let path = |file: &str| dir.0.join(file);
+        let migrations = migration_files_in_directory(&dir.0).unwrap();
+        assert_eq!(
+            migrations,
+            vec![
+                MigrationFiles::Flyway {
+                    up: path("V2.1__audit.sql"),
+                    undo: None
+                },
+                MigrationFiles::Flyway {
+                    up: path("V3__orders.sql"),
+                    undo: Some(path("U3__orders.sql"))
+                },
+                MigrationFiles::SingleFile(path("20200101000000_init.sql")),
+                MigrationFiles::Directory(path("2020-01-02-000000_users")),
+                MigrationFiles::Repeatable {
+                    path: path("R_functions"),
+                    script: path("R_functions/up.sql")
+                },
+                MigrationFiles::Repeatable {
+                    path: path("repeatable/triggers.sql"),
+                    script: path("repeatable/triggers.sql"),
+                },
+                MigrationFiles::Repeatable {
+                    path: path("R__views.sql"),
+                    script: path("R__views.sql")
+                },
+            ]
+        );
+        let versions: Vec<_> = migrations.iter().map(|m| m.version().unwrap()).collect();
+        assert_eq!(
+            versions,
+            vec![
+                padded(&["2", "1"]),
+                padded(&["3"]),
+                "20200101000000".to_string(),
+                "20200102000000".to_string(),
+                "functions".to_string(),
+                "triggers".to_string(),
+                "views".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn orphan_undo_file_is_rejected() {
+        let dir = TempDir::new("orphan_undo", &["V1__init.sql", "U2__users.sql"]);
+        match migration_files_in_directory(&dir.0) {
+            Err(Error::UnknownMigrationFormat(path)) => assert_eq!(path, dir.0.join("U2__users.sql")),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_files_are_rejected() {
+        let cases: [&[&str]; 3] = [&["20200101000000_init.txt"], &["R_views/"], &["repeatable/views/"]];
+        for (i, files) in cases.iter().enumerate() {
+            let dir = TempDir::new(&format!("unknown_{}", i), files);
+            let result = migration_files_in_directory(&dir.0);
+            assert!(matches!(result, Err(Error::UnknownMigrationFormat(_))), "{:?}", files);
+        }
+    }
+
+    #[test]
+    fn duplicate_versions_are_rejected() {
+        let cases: [&[&str]; 3] = [
+            &["20200101000000_a.sql", "2020-01-01-000000_b/up.sql"],
+            &["V1__a.sql", "V1.0__b.sql", "V01__c.sql"],
+            &["R__views.sql", "repeatable/views.sql"],
+        ];
+        for files in cases {
+            let dir = TempDir::new("duplicates", files);
+            let result = migration_files_in_directory(&dir.0);
+            assert!(
+                matches!(result, Err(Error::DuplicateMigrationVersion(..))),
+                "{:?}: {:?}",
+                files,
+                result
+            );
+        }
+    }
+}
@@ -1,77 +1,795 @@
+pub mod connection;
 pub mod error;
+pub mod files;
+pub mod lock;
 pub mod migration;
+pub mod migration_set;
+pub mod observer;
 pub mod runner;
+pub mod sql;
+pub mod status;
+pub mod tenant;
 
 pub use async_trait::async_trait;
+use connection::*;
 use error::*;
 use migration::*;
+use observer::*;
 use runner::*;
+use sql::*;
+use status::*;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::env;
+use std::error::Error as _;
 use std::fs::DirEntry;
-use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio_postgres::error::{DbError, ErrorPosition};
+use tokio_postgres::Client;
 
 /// Run all pending migrations in the given list. Apps should likely be calling
-pub async fn run_migrations<T>(
-    pool: DbConnectionPool,
+pub async fn run_migrations<T, S>(
+    source: S,
     migrations: Vec<T>,
-    output: &mut (dyn Write + Send + Sync),
+    observer: &(dyn MigrationObserver + Sync),
 ) -> Result<(), Error>
 where
     T: Migration + Send + Sync,
+    S: ConnectionSource,
 {
-    let runner = MigrationsRunner::from_pool(pool.clone());
-    runner.setup_database().await?;
-    let already_run = runner.previously_run_migration_versions().await?;
-    let mut pending_migrations: Vec<_> = migrations
-        .into_iter()
-        .filter(|m| !already_run.contains(&m.version().to_string()))
-        .collect();
+    run_migrations_with_config(source, migrations, MigrationsConfig::default(), observer).await
+}
+
+/// Same as `run_migrations`, using the given runner configuration. When `config.lock` is set,
+/// the advisory lock is held from before the migrations table is read until the last pending
+/// migration has been applied.
+pub async fn run_migrations_with_config<T, S>(
+    source: S,
+    migrations: Vec<T>,
+    config: MigrationsConfig,
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<(), Error>
+where
+    T: Migration + Send + Sync,
+    S: ConnectionSource,
+{
+    let runner = MigrationsRunner::new(source, config);
+    if runner.config().dry_run {
+        return apply_pending_migrations(&runner, migrations, observer).await;
+    }
+    let lock = runner.acquire_lock().await?;
+    let result = apply_pending_migrations(&runner, migrations, observer).await;
+    let released = match lock {
+        Some(lock) => lock.release().await,
+        None => Ok(()),
+    };
+    // A failure to release the lock is only reported if it is the only error
+    result.and(released)
+}
+
+async fn apply_pending_migrations<T, S>(
+    runner: &MigrationsRunner<S>,
+    migrations: Vec<T>,
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<(), Error>
+where
+    T: Migration + Send + Sync,
+    S: ConnectionSource,
+{
+    let dry_run = runner.config().dry_run;
+    if !dry_run {
+        runner.setup_database().await?;
+    }
+    let (repeatable, migrations): (Vec<_>, Vec<_>) = migrations.into_iter().partition(|m| m.repeatable());
+    let already_run = runner.previously_run_migration_checksums().await?;
+    let drifted = drifted_migrations(&migrations, &already_run)?;
+    if !drifted.is_empty() {
+        if !runner.config().repair_checksums {
+            return Err(Error::ChecksumMismatch(
+                drifted.iter().map(|m| m.version().to_string()).collect(),
+            ));
+        }
+        for migration in drifted {
+            observer.checksum_repaired(migration)?;
+            if !dry_run {
+                let checksum = migration.checksum()?;
+                runner
+                    .update_migration_checksum(migration.version(), checksum.as_deref())
+                    .await?;
+            }
+        }
+    }
+
+    let mut pending_migrations = Vec::new();
+    for migration in migrations {
+        if already_run.contains_key(migration.version()) {
+            observer.skipped(&migration)?;
+        } else {
+            pending_migrations.push(migration);
+        }
+    }
 
     pending_migrations.sort_by(|a, b| a.version().cmp(b.version()));
-    for migration in pending_migrations {
-        run_migration(&runner, &migration, output).await?;
+    check_out_of_order(
+        runner.config().out_of_order,
+        &pending_migrations,
+        &already_run,
+        observer,
+    )?;
+
+    // Repeatable migrations run after the versioned ones, when they changed since they last ran
+    let last_run = runner.repeatable_migration_checksums().await?;
+    let mut changed_repeatable = Vec::new();
+    for migration in repeatable {
+        if repeatable_changed(&migration, &last_run)? {
+            changed_repeatable.push(migration);
+        } else {
+            observer.skipped(&migration)?;
+        }
+    }
+    changed_repeatable.sort_by(|a, b| a.version().cmp(b.version()));
+    pending_migrations.extend(changed_repeatable);
+
+    if dry_run {
+        for migration in pending_migrations {
+            observer.dry_run(&migration, migration.up_sql()?.as_deref())?;
+        }
+    } else if runner.config().single_transaction {
+        for migration in &pending_migrations {
+            if migration.no_transaction()? {
+                return Err(Error::NoTransactionMigration(migration.version().to_string()));
+            }
+        }
+        let mut conn = runner.get_connection().await?;
+        let transaction = runner.transaction(&mut conn).await?;
+        for migration in pending_migrations {
+            run_migration_in_transaction(runner, &transaction, &migration, observer).await?;
+        }
+        transaction.commit().await?;
+    } else {
+        for migration in pending_migrations {
+            run_migration(runner, &migration, observer).await?;
+        }
     }
     Ok(())
 }
 
-pub async fn run_migration(
-    runner: &MigrationsRunner,
+pub async fn run_migration<S: ConnectionSource>(
+    runner: &MigrationsRunner<S>,
     migration: &(dyn Migration + Send + Sync),
-    output: &mut (dyn Write + Send + Sync),
+    observer: &(dyn MigrationObserver + Sync),
 ) -> Result<(), Error> {
-    let mut conn = runner.get_pooled_conn().await?;
-    let transaction = conn.transaction().await?;
-    if migration.version() != "00000000000000" {
-        writeln!(output, "Running migration {}", name(&migration))?;
+    let mut conn = runner.get_connection().await?;
+    if migration.no_transaction()? {
+        return run_migration_without_transaction(runner, &mut conn, migration, observer).await;
     }
-    if let Err(e) = migration.run(&transaction).await {
-        writeln!(output, "Executing migration script {}", file_name(&migration, "up.sql"))?;
+    let transaction = runner.transaction(&mut conn).await?;
+    run_migration_in_transaction(runner, &transaction, migration, observer).await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+async fn run_migration_without_transaction<S: ConnectionSource>(
+    runner: &MigrationsRunner<S>,
+    conn: &mut Client,
+    migration: &(dyn Migration + Send + Sync),
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<(), Error> {
+    observer.started(&migration)?;
+    let start = Instant::now();
+    let sql = migration.up_sql()?;
+    let search_path = runner.set_search_path(conn).await?;
+    let result = match sql {
+        Some(sql) => run_statements(&*conn, migration, "up.sql", &sql, observer).await,
+        None => migration
+            .run_without_transaction(conn)
+            .await
+            .map_err(|e| script_error(migration, "up.sql", migration.up_sql(), e)),
+    };
+    let restored = runner.restore_search_path(conn, search_path).await;
+    if let Err(e) = result {
+        observer.failed(&migration, &e)?;
         return Err(e);
     }
-    runner.insert_new_migration(&transaction, migration.version()).await?;
+    restored?;
+    let duration = start.elapsed();
+    let transaction = runner.transaction(conn).await?;
+    record_migration(runner, &transaction, migration, duration).await?;
+    transaction.commit().await?;
+    observer.succeeded(&migration, duration)?;
+    Ok(())
+}
+
+async fn run_migration_in_transaction<S: ConnectionSource>(
+    runner: &MigrationsRunner<S>,
+    transaction: &DbTransaction<'_>,
+    migration: &(dyn Migration + Send + Sync),
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<(), Error> {
+    observer.started(&migration)?;
+    let start = Instant::now();
+    let result = match split_script(runner, || migration.up_sql())? {
+        Some(sql) => run_statements(transaction, migration, "up.sql", &sql, observer).await,
+        None => migration
+            .run(transaction)
+            .await
+            .map_err(|e| script_error(migration, "up.sql", migration.up_sql(), e)),
+    };
+    if let Err(e) = result {
+        observer.failed(&migration, &e)?;
+        return Err(e);
+    }
+    let duration = start.elapsed();
+    record_migration(runner, transaction, migration, duration).await?;
+    observer.succeeded(&migration, duration)?;
+    Ok(())
+}
+
+/// Records a migration which has just run in the migrations table
+async fn record_migration<S: ConnectionSource>(
+    runner: &MigrationsRunner<S>,
+    transaction: &DbTransaction<'_>,
+    migration: &(dyn Migration + Send + Sync),
+    duration: Duration,
+) -> Result<(), Error> {
+    let checksum = migration.checksum()?;
+    let migration_name = name(&migration).to_string();
+    if migration.repeatable() {
+        runner
+            .record_repeatable_migration(
+                transaction,
+                migration.version(),
+                &migration_name,
+                checksum.as_deref(),
+                duration,
+            )
+            .await
+    } else {
+        runner
+            .insert_new_migration(
+                transaction,
+                migration.version(),
+                &migration_name,
+                checksum.as_deref(),
+                duration,
+            )
+            .await
+    }
+}
+
+/// Returns the script to run statement by statement, if `split_statements` is set and the
+/// migration is backed by one
+fn split_script<S, F>(runner: &MigrationsRunner<S>, script: F) -> Result<Option<String>, Error>
+where
+    S: ConnectionSource,
+    F: FnOnce() -> Result<Option<String>, Error>,
+{
+    if runner.config().split_statements {
+        script()
+    } else {
+        Ok(None)
+    }
+}
+
+/// Runs the statements of a migration script one by one, reporting each to the observer
+async fn run_statements(
+    executor: &dyn StatementExecutor,
+    migration: &(dyn Migration + Send + Sync),
+    sql_file: &str,
+    sql: &str,
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<(), Error> {
+    if sql.is_empty() {
+        return Err(Error::EmptyMigration);
+    }
+    for statement in split_statements(sql) {
+        observer.statement_started(&migration, statement.sql)?;
+        let start = Instant::now();
+        let result = match statement.copy_data {
+            Some(data) => executor.copy_in(statement.sql, data).await,
+            None => executor.batch_execute(statement.sql).await,
+        };
+        if let Err(e) = result {
+            return Err(statement_error(
+                migration,
+                sql_file,
+                Some(sql),
+                Some(statement.offset),
+                e.into(),
+            ));
+        }
+        observer.statement_succeeded(&migration, statement.sql, start.elapsed())?;
+    }
+    Ok(())
+}
+
+/// `statement_error` for an error raised while a migration ran its whole script
+fn script_error(
+    migration: &(dyn Migration + Send + Sync),
+    sql_file: &str,
+    sql: Result<Option<String>, Error>,
+    error: Error,
+) -> Error {
+    let sql = sql.ok().flatten();
+    statement_error(migration, sql_file, sql.as_deref(), None, error)
+}
+
+/// Turns a database error raised by a migration script into `Error::StatementFailed`, locating
+/// the failing statement in the script. `statement_offset` is the offset of the statement which
+/// failed when statements are run one by one. Other errors are returned unchanged.
+fn statement_error(
+    migration: &(dyn Migration + Send + Sync),
+    sql_file: &str,
+    sql: Option<&str>,
+    statement_offset: Option<usize>,
+    error: Error,
+) -> Error {
+    let source = match error {
+        Error::Postgres(source) => source,
+        error => return error,
+    };
+    let db_error = match source.source().and_then(|e| e.downcast_ref::<DbError>()) {
+        Some(db_error) => db_error,
+        None => return Error::Postgres(source),
+    };
+    let position = match db_error.position() {
+        Some(&ErrorPosition::Original(position)) => Some(position as usize),
+        _ => None,
+    };
+    let (statement, line, column) = match sql.and_then(|sql| locate_error(sql, statement_offset, position)) {
+        Some((statement, line, column)) => {
+            let line_offset = migration.script_line_offset(sql_file).unwrap_or(0);
+            (statement, Some(line_offset + line), Some(column))
+        }
+        None => (None, None, None),
+    };
+    let message = db_error.message().to_string();
+    let detail = db_error.detail().map(str::to_string);
+    let hint = db_error.hint().map(str::to_string);
+    Error::StatementFailed(Box::new(StatementError {
+        migration: name(&migration).to_string(),
+        script: file_name(&migration, sql_file).to_string(),
+        statement,
+        line,
+        column,
+        message,
+        detail,
+        hint,
+        source,
+    }))
+}
+
+/// Returns the statement of `sql` in which an error occurred, along with the line and column of
+/// the error. `position` is the 1-based character position reported by the database, relative to
+/// the statement at `statement_offset` if it is set, or to the whole script. Without a position,
+/// the start of the statement is used.
+fn locate_error(
+    sql: &str,
+    statement_offset: Option<usize>,
+    position: Option<usize>,
+) -> Option<(Option<String>, usize, usize)> {
+    let base = statement_offset.unwrap_or(0);
+    let offset = match position {
+        Some(position) => base + sql[base..].char_indices().nth(position.checked_sub(1)?)?.0,
+        None => statement_offset?,
+    };
+    let before = &sql[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before[before.rfind('\n').map_or(0, |i| i + 1)..].chars().count() + 1;
+    let statement = split_statements(sql)
+        .into_iter()
+        .find(|s| s.offset <= offset && offset < s.offset + s.sql.len())
+        .map(|s| s.sql.to_string());
+    Some((statement, line, column))
+}
+
+/// Compares the given list of migrations with the migrations recorded in the database, without
+/// running anything. The migrations table is only read: if it does not exist yet, every migration
+/// is pending.
+pub async fn migration_status<T, S>(source: S, migrations: Vec<T>) -> Result<MigrationStatus, Error>
+where
+    T: Migration + Send + Sync,
+    S: ConnectionSource,
+{
+    migration_status_with_config(source, migrations, MigrationsConfig::default()).await
+}
+
+/// Same as `migration_status`, using the given runner configuration.
+pub async fn migration_status_with_config<T, S>(
+    source: S,
+    migrations: Vec<T>,
+    config: MigrationsConfig,
+) -> Result<MigrationStatus, Error>
+where
+    T: Migration + Send + Sync,
+    S: ConnectionSource,
+{
+    let runner = MigrationsRunner::new(source, config);
+    let (repeatable, migrations): (Vec<_>, Vec<_>) = migrations.into_iter().partition(|m| m.repeatable());
+    let already_run = runner.previously_run_migration_checksums().await?;
+    let mut status = MigrationStatus {
+        checksum_mismatches: drifted_migrations(&migrations, &already_run)?
+            .iter()
+            .map(|m| m.version().to_string())
+            .collect(),
+        ..MigrationStatus::default()
+    };
+
+    for applied in runner.applied_migrations().await? {
+        if migrations.iter().any(|m| m.version() == applied.version) {
+            status.applied.push(applied);
+        } else {
+            status.missing.push(applied);
+        }
+    }
+
+    status.pending = migrations
+        .iter()
+        .map(|m| m.version().to_string())
+        .filter(|v| !already_run.contains_key(v))
+        .collect();
+    status.pending.sort();
+
+    let last_run = runner.repeatable_migration_checksums().await?;
+    for migration in &repeatable {
+        if repeatable_changed(migration, &last_run)? {
+            status.pending_repeatable.push(migration.version().to_string());
+        }
+    }
+    status.pending_repeatable.sort();
+    Ok(status)
+}
+
+/// Whether a repeatable migration never ran, or its checksum differs from the one recorded when
+/// it last ran
+fn repeatable_changed<T: Migration>(migration: &T, last_run: &HashMap<String, Option<String>>) -> Result<bool, Error> {
+    match last_run.get(migration.version()) {
+        Some(checksum) => Ok(*checksum != migration.checksum()?),
+        None => Ok(true),
+    }
+}
+
+/// Applies the out-of-order policy to the pending migrations older than the latest applied one
+fn check_out_of_order<T: Migration>(
+    policy: OutOfOrderPolicy,
+    pending_migrations: &[T],
+    already_run: &HashMap<String, Option<String>>,
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<(), Error> {
+    let latest_version = match already_run.keys().max() {
+        Some(version) => version,
+        None => return Ok(()),
+    };
+    let out_of_order: Vec<_> = pending_migrations
+        .iter()
+        .filter(|m| m.version() < latest_version.as_str())
+        .map(|m| m.version().to_string())
+        .collect();
+    if out_of_order.is_empty() {
+        return Ok(());
+    }
+    match policy {
+        OutOfOrderPolicy::Allow => Ok(()),
+        OutOfOrderPolicy::Warn => observer.out_of_order(&out_of_order, latest_version),
+        OutOfOrderPolicy::Reject => Err(Error::OutOfOrderMigrations(out_of_order)),
+    }
+}
+
+/// Returns the already applied migrations whose stored checksum no longer matches their script.
+/// Migrations recorded without a checksum are not checked.
+fn drifted_migrations<'a, T>(
+    migrations: &'a [T],
+    already_run: &HashMap<String, Option<String>>,
+) -> Result<Vec<&'a T>, Error>
+where
+    T: Migration + Send + Sync,
+{
+    let mut drifted = Vec::new();
+    for migration in migrations {
+        if let Some(Some(stored)) = already_run.get(migration.version()) {
+            if migration.checksum()?.is_some_and(|checksum| &checksum != stored) {
+                drifted.push(migration);
+            }
+        }
+    }
+    Ok(drifted)
+}
+
+/// Reverts the given migration in its own transaction. Returns `Error::IrreversibleMigration`
+/// without connecting to the database if the migration can't be reverted.
+pub async fn revert_migration<S: ConnectionSource>(
+    runner: &MigrationsRunner<S>,
+    migration: &(dyn Migration + Send + Sync),
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<(), Error> {
+    ensure_reversible(migration)?;
+    let mut conn = runner.get_connection().await?;
+    if migration.no_transaction_revert()? {
+        return revert_migration_without_transaction(runner, &mut conn, migration, observer).await;
+    }
+    let transaction = runner.transaction(&mut conn).await?;
+    revert_migration_in_transaction(runner, &transaction, migration, observer).await?;
     transaction.commit().await?;
     Ok(())
 }
 
-pub async fn revert_migration(
-    runner: &MigrationsRunner,
+async fn revert_migration_without_transaction<S: ConnectionSource>(
+    runner: &MigrationsRunner<S>,
+    conn: &mut Client,
     migration: &(dyn Migration + Send + Sync),
-    output: &mut (dyn Write + Send + Sync),
+    observer: &(dyn MigrationObserver + Sync),
 ) -> Result<(), Error> {
-    let mut conn = runner.get_pooled_conn().await?;
-    let transaction = conn.transaction().await?;
-    writeln!(output, "Rolling back migration {}", name(&migration))?;
-    if let Err(e) = migration.revert(&transaction).await {
-        writeln!(
-            output,
-            "Executing migration script {}",
-            file_name(&migration, "down.sql")
-        )?;
+    observer.reverting(&migration)?;
+    let start = Instant::now();
+    let sql = migration.down_sql()?;
+    let search_path = runner.set_search_path(conn).await?;
+    let result = match sql {
+        Some(sql) => run_statements(&*conn, migration, "down.sql", &sql, observer).await,
+        None => migration
+            .revert_without_transaction(conn)
+            .await
+            .map_err(|e| script_error(migration, "down.sql", migration.down_sql(), e)),
+    };
+    let restored = runner.restore_search_path(conn, search_path).await;
+    if let Err(e) = result {
+        observer.revert_failed(&migration, &e)?;
         return Err(e);
     }
+    restored?;
+    let transaction = runner.transaction(conn).await?;
     runner.delete_migration(&transaction, migration.version()).await?;
     transaction.commit().await?;
+    observer.reverted(&migration, start.elapsed())?;
+    Ok(())
+}
+
+async fn revert_migration_in_transaction<S: ConnectionSource>(
+    runner: &MigrationsRunner<S>,
+    transaction: &DbTransaction<'_>,
+    migration: &(dyn Migration + Send + Sync),
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<(), Error> {
+    observer.reverting(&migration)?;
+    let start = Instant::now();
+    let result = match split_script(runner, || migration.down_sql())? {
+        Some(sql) => run_statements(transaction, migration, "down.sql", &sql, observer).await,
+        None => migration
+            .revert(transaction)
+            .await
+            .map_err(|e| script_error(migration, "down.sql", migration.down_sql(), e)),
+    };
+    if let Err(e) = result {
+        observer.revert_failed(&migration, &e)?;
+        return Err(e);
+    }
+    runner.delete_migration(transaction, migration.version()).await?;
+    observer.reverted(&migration, start.elapsed())?;
+    Ok(())
+}
+
+/// Reverts the most recently run migration from the given list. Returns the version of the
+/// migration which was reverted, or `Error::NoMigrationRun` if no migrations have been run yet,
+/// and `Error::IrreversibleMigration` if the migration can't be reverted.
+pub async fn revert_latest_migration<T, S>(
+    source: S,
+    migrations: Vec<T>,
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<String, Error>
+where
+    T: Migration + Send + Sync,
+    S: ConnectionSource,
+{
+    revert_latest_migration_with_config(source, migrations, MigrationsConfig::default(), observer).await
+}
+
+/// Same as `revert_latest_migration`, using the given runner configuration.
+pub async fn revert_latest_migration_with_config<T, S>(
+    source: S,
+    migrations: Vec<T>,
+    config: MigrationsConfig,
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<String, Error>
+where
+    T: Migration + Send + Sync,
+    S: ConnectionSource,
+{
+    let runner = MigrationsRunner::new(source, config);
+    let latest_version = runner
+        .latest_run_migration_version()
+        .await?
+        .ok_or(Error::NoMigrationRun)?;
+    let migration = migrations
+        .iter()
+        .find(|m| m.version() == latest_version)
+        .ok_or_else(|| Error::UnknownMigrationVersion(latest_version.clone()))?;
+    revert_migration(&runner, migration, observer).await?;
+    Ok(latest_version)
+}
+
+/// Reverts the most recently run migration from the given list and runs it again. Both steps
+/// happen in a single transaction, so if running the migration fails the database is left as it
+/// was. Returns the version of the migration, or `Error::NoMigrationRun` if no migrations have
+/// been run yet. Migrations that must run outside of a transaction, or are irreversible, can't be
+/// redone.
+pub async fn redo_latest<T, S>(
+    source: S,
+    migrations: Vec<T>,
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<String, Error>
+where
+    T: Migration + Send + Sync,
+    S: ConnectionSource,
+{
+    redo_latest_with_config(source, migrations, MigrationsConfig::default(), observer).await
+}
+
+/// Same as `redo_latest`, using the given runner configuration.
+pub async fn redo_latest_with_config<T, S>(
+    source: S,
+    migrations: Vec<T>,
+    config: MigrationsConfig,
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<String, Error>
+where
+    T: Migration + Send + Sync,
+    S: ConnectionSource,
+{
+    let runner = MigrationsRunner::new(source, config);
+    let latest_version = runner
+        .latest_run_migration_version()
+        .await?
+        .ok_or(Error::NoMigrationRun)?;
+    let migration = migrations
+        .iter()
+        .find(|m| m.version() == latest_version)
+        .ok_or_else(|| Error::UnknownMigrationVersion(latest_version.clone()))?;
+    if migration.no_transaction()? || migration.no_transaction_revert()? {
+        return Err(Error::NoTransactionMigration(latest_version));
+    }
+    ensure_reversible(migration)?;
+
+    // Running the migration again records it, in a table possibly created by an older version
+    runner.setup_database().await?;
+    let mut conn = runner.get_connection().await?;
+    let transaction = runner.transaction(&mut conn).await?;
+    revert_migration_in_transaction(&runner, &transaction, migration, observer).await?;
+    run_migration_in_transaction(&runner, &transaction, migration, observer).await?;
+    transaction.commit().await?;
+    Ok(latest_version)
+}
+
+/// Reverts every run migration whose version is later than `target_version`, newest first. The
+/// target migration itself stays applied. Returns the reverted versions in the order they were
+/// reverted.
+///
+/// Nothing is reverted if the target, or any of the migrations to revert, is missing from the
+/// given list; `Error::UnknownMigrationVersion` is returned instead. Likewise, nothing is
+/// reverted if any of them is irreversible; `Error::IrreversibleMigration` is returned instead.
+pub async fn revert_to_version<T, S>(
+    source: S,
+    migrations: Vec<T>,
+    target_version: &str,
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<Vec<String>, Error>
+where
+    T: Migration + Send + Sync,
+    S: ConnectionSource,
+{
+    revert_to_version_with_config(
+        source,
+        migrations,
+        MigrationsConfig::default(),
+        target_version,
+        observer,
+    )
+    .await
+}
+
+/// Same as `revert_to_version`, using the given runner configuration.
+pub async fn revert_to_version_with_config<T, S>(
+    source: S,
+    migrations: Vec<T>,
+    config: MigrationsConfig,
+    target_version: &str,
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<Vec<String>, Error>
+where
+    T: Migration + Send + Sync,
+    S: ConnectionSource,
+{
+    // Flyway versions are recorded zero-padded, they are also accepted as written in file names
+    let flyway_version = files::flyway_version_number(target_version);
+    let target_version = migrations
+        .iter()
+        .map(|m| m.version())
+        .find(|&version| version == target_version || Some(version) == flyway_version.as_deref())
+        .ok_or_else(|| Error::UnknownMigrationVersion(target_version.to_string()))?;
+    let runner = MigrationsRunner::new(source, config);
+    let versions = runner.run_migration_versions_after(target_version).await?;
+    revert_versions(&runner, &migrations, &versions, observer).await?;
+    Ok(versions)
+}
+
+/// Reverts the `count` latest run migrations, newest first. Returns the reverted versions in the
+/// order they were reverted, or `Error::NoMigrationRun` if no migrations have been run yet. Nothing
+/// is reverted when `count` is 0.
+///
+/// Nothing is reverted if any of the migrations to revert is missing from the given list, or is
+/// irreversible; `Error::UnknownMigrationVersion` or `Error::IrreversibleMigration` is returned
+/// instead.
+pub async fn revert_last<T, S>(
+    source: S,
+    migrations: Vec<T>,
+    count: usize,
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<Vec<String>, Error>
+where
+    T: Migration + Send + Sync,
+    S: ConnectionSource,
+{
+    revert_last_with_config(source, migrations, MigrationsConfig::default(), count, observer).await
+}
+
+/// Same as `revert_last`, using the given runner configuration.
+pub async fn revert_last_with_config<T, S>(
+    source: S,
+    migrations: Vec<T>,
+    config: MigrationsConfig,
+    count: usize,
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<Vec<String>, Error>
+where
+    T: Migration + Send + Sync,
+    S: ConnectionSource,
+{
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    let runner = MigrationsRunner::new(source, config);
+    let count = i64::try_from(count).unwrap_or(i64::MAX);
+    let versions = runner.latest_run_migration_versions(count).await?;
+    if versions.is_empty() {
+        return Err(Error::NoMigrationRun);
+    }
+    revert_versions(&runner, &migrations, &versions, observer).await?;
+    Ok(versions)
+}
+
+async fn revert_versions<T, S>(
+    runner: &MigrationsRunner<S>,
+    migrations: &[T],
+    versions: &[String],
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<(), Error>
+where
+    T: Migration + Send + Sync,
+    S: ConnectionSource,
+{
+    let to_revert = versions
+        .iter()
+        .map(|version| {
+            migrations
+                .iter()
+                .find(|m| m.version() == version)
+                .ok_or_else(|| Error::UnknownMigrationVersion(version.clone()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for &migration in &to_revert {
+        ensure_reversible(migration)?;
+    }
+
+    for migration in to_revert {
+        revert_migration(runner, migration, observer).await?;
+    }
+    Ok(())
+}
+
+/// Returns `Error::IrreversibleMigration` if the given migration can't be reverted
+fn ensure_reversible(migration: &(dyn Migration + Send + Sync)) -> Result<(), Error> {
+    if migration.irreversible()? {
+        return Err(Error::IrreversibleMigration(migration.version().to_string()));
+    }
     Ok(())
 }
 
@@ -91,7 +809,7 @@ pub fn search_for_migrations_directory(path: &Path) -> Result<PathBuf, Error> {
         Ok(migration_path)
     } else {
         path.parent()
-            .map(|p| search_for_migrations_directory(p))
+            .map(search_for_migrations_directory)
             .unwrap_or_else(|| Err(Error::MigrationDirectoryNotFound(path.into())))
             .map_err(|_| Error::MigrationDirectoryNotFound(path.into()))
     }
@@ -115,11 +833,164 @@ pub fn migration_paths_in_directory(path: &Path) -> Result<Vec<DirEntry>, Error>
 }
 
 pub fn version_from_path(path: &Path) -> Result<String, Error> {
-    path.file_name()
+    let file_name = path
+        .file_name()
         .unwrap_or_else(|| panic!("Can't get file name from path `{:?}`", path))
-        .to_string_lossy()
+        .to_string_lossy();
+    if let Some((_, version)) = files::flyway_version(&file_name) {
+        return Ok(version);
+    }
+    file_name
         .split('_')
         .next()
         .map(|s| Ok(s.replace('-', "")))
         .unwrap_or_else(|| Err(Error::UnknownMigrationFormat(path.to_path_buf())))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = "CREATE TABLE a (id INT);\nSELECT * FROM nope;\n";
+
+    #[test]
+    fn locate_error_finds_statement_line_and_column() {
+        let nope = Some("SELECT * FROM nope;".to_string());
+        let cases = [
+            // Position relative to the whole script
+            (SCRIPT, None, Some(40), Some((nope.clone(), 2, 15))),
+            // Position relative to the statement run on its own
+            (SCRIPT, Some(25), Some(15), Some((nope.clone(), 2, 15))),
+            // No position: the start of the statement run on its own
+            (SCRIPT, Some(25), None, Some((nope, 2, 1))),
+            (
+                SCRIPT,
+                Some(0),
+                Some(1),
+                Some((Some("CREATE TABLE a (id INT);".to_string()), 1, 1)),
+            ),
+            // Positions count characters, not bytes
+            (
+                "SELECT 'é';\nSELECT nope;",
+                None,
+                Some(20),
+                Some((Some("SELECT nope;".to_string()), 2, 8)),
+            ),
+            (
+                "SELECT 'é', nope;",
+                None,
+                Some(13),
+                Some((Some("SELECT 'é', nope;".to_string()), 1, 13)),
+            ),
+        ];
+        for (sql, statement_offset, position, expected) in cases {
+            assert_eq!(
+                locate_error(sql, statement_offset, position),
+                expected,
+                "{:?} at {:?}",
+                sql,
+                (statement_offset, position)
+            );
+        }
+    }
+
+    #[test]
+    fn locate_error_without_location() {
+        assert_eq!(locate_error(SCRIPT, None, None), None);
+        assert_eq!(locate_error(SCRIPT, None, Some(0)), None);
+        assert_eq!(locate_error(SCRIPT, None, Some(1000)), None);
+        assert_eq!(locate_error(SCRIPT, Some(25), Some(1000)), None);
+    }
+
+    #[test]
+    fn locate_error_outside_of_statements() {
+        // The error is in the trailing newline, which belongs to no statement
+        assert_eq!(locate_error(SCRIPT, None, Some(45)), Some((None, 2, 20)));
+    }
+
+    struct TestMigration(&'static str);
+
+    #[async_trait]
+    impl Migration for TestMigration {
+        fn version(&self) -> &str {
+            self.0
+        }
+
+        async fn run(&self, _transaction: &DbTransaction<'_>) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn revert(&self, _transaction: &DbTransaction<'_>) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    /// Records the out-of-order migrations it is told about
+    #[derive(Default)]
+    struct OutOfOrderObserver(std::sync::Mutex<Vec<(Vec<String>, String)>>);
+
+    impl MigrationObserver for OutOfOrderObserver {
+        fn out_of_order(&self, versions: &[String], latest_version: &str) -> Result<(), Error> {
+            let reported = (versions.to_vec(), latest_version.to_string());
+            self.0.lock().unwrap().push(reported);
+            Ok(())
+        }
+    }
+
+    fn history(versions: &[&str]) -> HashMap<String, Option<String>> {
+        versions.iter().map(|v| (v.to_string(), None)).collect()
+    }
+
+    #[test]
+    fn check_out_of_order_without_history() {
+        let pending = [TestMigration("1"), TestMigration("2")];
+        for policy in [
+            OutOfOrderPolicy::Allow,
+            OutOfOrderPolicy::Warn,
+            OutOfOrderPolicy::Reject,
+        ] {
+            let observer = OutOfOrderObserver::default();
+            assert!(check_out_of_order(policy, &pending, &history(&[]), &observer).is_ok());
+            assert!(observer.0.lock().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn check_out_of_order_with_newer_pending_migrations() {
+        let pending = [TestMigration("3"), TestMigration("4")];
+        for policy in [
+            OutOfOrderPolicy::Allow,
+            OutOfOrderPolicy::Warn,
+            OutOfOrderPolicy::Reject,
+        ] {
+            let observer = OutOfOrderObserver::default();
+            assert!(check_out_of_order(policy, &pending, &history(&["1", "2"]), &observer).is_ok());
+            assert!(observer.0.lock().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn check_out_of_order_with_older_pending_migrations() {
+        let pending = [TestMigration("1"), TestMigration("3"), TestMigration("5")];
+        let already_run = history(&["2", "4"]);
+        let out_of_order = vec!["1".to_string(), "3".to_string()];
+
+        let observer = OutOfOrderObserver::default();
+        assert!(check_out_of_order(OutOfOrderPolicy::Allow, &pending, &already_run, &observer).is_ok());
+        assert!(observer.0.lock().unwrap().is_empty());
+
+        let observer = OutOfOrderObserver::default();
+        assert!(check_out_of_order(OutOfOrderPolicy::Warn, &pending, &already_run, &observer).is_ok());
+        assert_eq!(
+            *observer.0.lock().unwrap(),
+            vec![(out_of_order.clone(), "4".to_string())]
+        );
+
+        let observer = OutOfOrderObserver::default();
+        match check_out_of_order(OutOfOrderPolicy::Reject, &pending, &already_run, &observer) {
+            Err(Error::OutOfOrderMigrations(versions)) => assert_eq!(versions, out_of_order),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(observer.0.lock().unwrap().is_empty());
+    }
+}
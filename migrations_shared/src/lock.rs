@@ -0,0 +1,134 @@
+use crate::connection::*;
+use crate::error::*;
+use std::time::Duration;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::Client;
+
+/// Default advisory lock key used to serialize concurrent migration runs
+pub const DEFAULT_LOCK_KEY: i64 = 0x7067_6d69_6772_6174;
+
+/// What to do when the migrations lock is already held by another session
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockMode {
+    /// Block until the lock is released, or until the timeout elapses
+    Wait,
+    /// Return `Error::LockUnavailable` immediately
+    FailFast,
+}
+
+/// Postgres advisory lock taken around the whole pending-migration loop, so that several
+/// instances starting at once apply migrations one after another instead of racing
+#[derive(Clone, Debug)]
+pub struct AdvisoryLock {
+    pub key: i64,
+    pub mode: LockMode,
+    /// Maximum time to wait for the lock in `LockMode::Wait`, rounded up to whole milliseconds.
+    /// Waits forever when `None`.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for AdvisoryLock {
+    fn default() -> Self {
+        AdvisoryLock {
+            key: DEFAULT_LOCK_KEY,
+            mode: LockMode::Wait,
+            timeout: None,
+        }
+    }
+}
+
+impl AdvisoryLock {
+    /// Acquire the lock on the lock connection of the given source, or on its shared session if
+    /// it has none. The lock is bound to the session, so it is held until the returned guard is
+    /// released or the session ends.
+    pub async fn acquire<'a, S>(&self, source: &'a S) -> Result<MigrationsLock<'a, S>, Error>
+    where
+        S: ConnectionSource + ?Sized,
+    {
+        let client = source.lock_connection().await?;
+        match &client {
+            Some(client) => self.lock(client).await?,
+            None => self.lock(&*source.connection().await?).await?,
+        }
+        Ok(MigrationsLock {
+            source,
+            client,
+            key: self.key,
+        })
+    }
+
+    async fn lock(&self, client: &Client) -> Result<(), Error> {
+        match self.mode {
+            LockMode::Wait => {
+                if let Some(timeout) = self.timeout {
+                    let query = format!("SET lock_timeout = {}", lock_timeout_millis(timeout));
+                    client.batch_execute(&query).await?;
+                }
+                let query = "SELECT pg_advisory_lock($1)";
+                let result = client.execute(query, &[&self.key]).await;
+                if self.timeout.is_some() {
+                    client.batch_execute("RESET lock_timeout").await?;
+                }
+                if let Err(e) = result {
+                    if e.code() == Some(&SqlState::LOCK_NOT_AVAILABLE) {
+                        return Err(Error::LockUnavailable(self.key));
+                    }
+                    return Err(e.into());
+                }
+            }
+            LockMode::FailFast => {
+                let query = "SELECT pg_try_advisory_lock($1)";
+                let acquired: bool = client.query_one(query, &[&self.key]).await?.try_get(0)?;
+                if !acquired {
+                    return Err(Error::LockUnavailable(self.key));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `lock_timeout` setting for the given timeout. It is at least 1 millisecond, since 0 disables the
+/// timeout, and at most the largest value Postgres accepts.
+fn lock_timeout_millis(timeout: Duration) -> u128 {
+    timeout.as_nanos().div_ceil(1_000_000).clamp(1, i32::MAX as u128)
+}
+
+/// Guard for an acquired advisory lock. Dropping a lock held on a dedicated connection closes
+/// that connection, which also releases the lock, but `release` should be preferred.
+pub struct MigrationsLock<'a, S: ?Sized> {
+    source: &'a S,
+    client: Option<DbConnection<'a>>,
+    key: i64,
+}
+
+impl<S: ConnectionSource + ?Sized> MigrationsLock<'_, S> {
+    pub async fn release(self) -> Result<(), Error> {
+        let query = "SELECT pg_advisory_unlock($1)";
+        match &self.client {
+            Some(client) => client.execute(query, &[&self.key]).await?,
+            None => self.source.connection().await?.execute(query, &[&self.key]).await?,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_timeout_is_rounded_up_to_milliseconds() {
+        let cases = [
+            (Duration::from_nanos(1), 1),
+            (Duration::from_micros(999), 1),
+            (Duration::from_millis(1), 1),
+            (Duration::from_micros(1001), 2),
+            (Duration::from_secs(5), 5000),
+            (Duration::from_secs(u64::MAX), i32::MAX as u128),
+        ];
+        for (timeout, expected) in cases {
+            assert_eq!(lock_timeout_millis(timeout), expected, "{:?}", timeout);
+        }
+    }
+}
@@ -1,8 +1,18 @@
-use crate::runner::*;
 use crate::error::*;
+use crate::runner::*;
+use crate::sql::*;
 use async_trait::async_trait;
 use std::fmt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use tokio_postgres::Client;
+
+/// Header line marking a migration script that has to run outside of a transaction, e.g. one
+/// using `CREATE INDEX CONCURRENTLY`. It is looked for in the leading comments of the script: in a
+/// single-file migration it must come after the `-- +up` or `-- +down` line, a header at the top
+/// of the file is not part of either script and is ignored.
+pub const NO_TRANSACTION_HEADER: &str = "-- migrations:no-transaction";
+/// Header line marking a migration that can't be reverted, even though it has a down script
+pub const IRREVERSIBLE_HEADER: &str = "-- migrations:irreversible";
 
 #[async_trait]
 pub trait Migration {
@@ -16,43 +26,233 @@ pub trait Migration {
     fn file_path(&self) -> Option<&Path> {
         None
     }
+    /// Get the path of the file containing the given script, `up.sql` or `down.sql`. By default
+    /// this is the script inside `file_path`.
+    fn script_path(&self, sql_file: &str) -> Option<PathBuf> {
+        self.file_path().map(|path| path.join(sql_file))
+    }
+    /// Get the number of lines of `script_path` preceding the given script, when the script is
+    /// only a part of that file. Used to report errors at their line in the file.
+    fn script_line_offset(&self, _sql_file: &str) -> Result<usize, Error> {
+        Ok(0)
+    }
+    /// Get the SQL script applied by `run`, if this migration is backed by one
+    fn up_sql(&self) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+    /// Get the SQL script applied by `revert`, if this migration is backed by one
+    fn down_sql(&self) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+    /// Get the checksum recorded in the migrations table when this migration is run
+    fn checksum(&self) -> Result<Option<String>, Error> {
+        Ok(self.up_sql()?.map(|sql| checksum(&sql)))
+    }
+    /// Whether this migration has to be applied outside of a transaction, with
+    /// `run_without_transaction`. By default this is the case when `up_sql` starts with
+    /// `NO_TRANSACTION_HEADER`.
+    fn no_transaction(&self) -> Result<bool, Error> {
+        Ok(self.up_sql()?.is_some_and(|sql| has_no_transaction_header(&sql)))
+    }
+    /// Whether this migration can't be reverted. Reverting it fails with
+    /// `Error::IrreversibleMigration` before anything is run. By default this is the case when
+    /// `up_sql` starts with `IRREVERSIBLE_HEADER`.
+    fn irreversible(&self) -> Result<bool, Error> {
+        Ok(self.up_sql()?.is_some_and(|sql| has_irreversible_header(&sql)))
+    }
+    /// Whether this is a repeatable migration, e.g. one defining views or functions. Instead of
+    /// being applied once, it is applied after the versioned migrations whenever its checksum
+    /// differs from the one recorded when it last ran. Its `version` is its name, and it is never
+    /// reverted.
+    fn repeatable(&self) -> bool {
+        false
+    }
+    /// Apply this migration on a connection that isn't in a transaction. By default this executes
+    /// the statements of `up_sql` one by one, since PostgreSQL runs a query string containing
+    /// several statements as an implicit transaction. The runner does the same itself for
+    /// migrations backed by an SQL script, so this is only called for the others.
+    async fn run_without_transaction(&self, client: &Client) -> Result<(), Error> {
+        execute_statements(client, self.up_sql()?).await
+    }
+    /// Whether this migration has to be reverted outside of a transaction, with
+    /// `revert_without_transaction`, e.g. because its down script uses `DROP INDEX CONCURRENTLY`.
+    /// By default this is the case when `down_sql` starts with `NO_TRANSACTION_HEADER`, or when
+    /// the migration is applied outside of a transaction.
+    fn no_transaction_revert(&self) -> Result<bool, Error> {
+        if self.no_transaction()? {
+            return Ok(true);
+        }
+        Ok(self.down_sql()?.is_some_and(|sql| has_no_transaction_header(&sql)))
+    }
+    /// Revert this migration on a connection that isn't in a transaction. By default this executes
+    /// the statements of `down_sql` one by one, see `run_without_transaction`.
+    async fn revert_without_transaction(&self, client: &Client) -> Result<(), Error> {
+        execute_statements(client, self.down_sql()?).await
+    }
+}
+
+/// Executes the statements of a migration script one by one
+async fn execute_statements(client: &Client, sql: Option<String>) -> Result<(), Error> {
+    match sql {
+        Some(sql) if !sql.is_empty() => {
+            for statement in split_statements(&sql) {
+                match statement.copy_data {
+                    Some(data) => StatementExecutor::copy_in(client, statement.sql, data).await?,
+                    None => client.batch_execute(statement.sql).await?,
+                }
+            }
+            Ok(())
+        }
+        _ => Err(Error::EmptyMigration),
+    }
+}
+
+/// Whether the leading comments of a migration script contain `NO_TRANSACTION_HEADER`
+pub fn has_no_transaction_header(sql: &str) -> bool {
+    has_header(sql, NO_TRANSACTION_HEADER)
+}
+
+/// Whether the leading comments of a migration script contain `IRREVERSIBLE_HEADER`
+pub fn has_irreversible_header(sql: &str) -> bool {
+    has_header(sql, IRREVERSIBLE_HEADER)
+}
+
+fn has_header(sql: &str, header: &str) -> bool {
+    sql.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .take_while(|line| line.starts_with("--"))
+        .any(|line| line == header)
+}
+
+/// Hex encoded md5 hash of a migration script
+pub fn checksum(sql: &str) -> String {
+    format!("{:x}", md5::compute(sql))
 }
 
 #[async_trait]
 impl Migration for Box<dyn Migration + Send + Sync> {
     fn version(&self) -> &str {
-        (&**self).version()
+        (**self).version()
     }
 
     async fn run(&self, transaction: &DbTransaction<'_>) -> Result<(), Error> {
-        (&**self).run(transaction).await
+        (**self).run(transaction).await
     }
 
     async fn revert(&self, transaction: &DbTransaction<'_>) -> Result<(), Error> {
-        (&**self).revert(transaction).await
+        (**self).revert(transaction).await
     }
 
     fn file_path(&self) -> Option<&Path> {
-        (&**self).file_path()
+        (**self).file_path()
+    }
+
+    fn script_path(&self, sql_file: &str) -> Option<PathBuf> {
+        (**self).script_path(sql_file)
+    }
+
+    fn script_line_offset(&self, sql_file: &str) -> Result<usize, Error> {
+        (**self).script_line_offset(sql_file)
+    }
+
+    fn up_sql(&self) -> Result<Option<String>, Error> {
+        (**self).up_sql()
+    }
+
+    fn down_sql(&self) -> Result<Option<String>, Error> {
+        (**self).down_sql()
+    }
+
+    fn checksum(&self) -> Result<Option<String>, Error> {
+        (**self).checksum()
+    }
+
+    fn no_transaction(&self) -> Result<bool, Error> {
+        (**self).no_transaction()
+    }
+
+    fn irreversible(&self) -> Result<bool, Error> {
+        (**self).irreversible()
+    }
+
+    fn repeatable(&self) -> bool {
+        (**self).repeatable()
+    }
+
+    async fn run_without_transaction(&self, client: &Client) -> Result<(), Error> {
+        (**self).run_without_transaction(client).await
+    }
+
+    fn no_transaction_revert(&self) -> Result<bool, Error> {
+        (**self).no_transaction_revert()
+    }
+
+    async fn revert_without_transaction(&self, client: &Client) -> Result<(), Error> {
+        (**self).revert_without_transaction(client).await
     }
 }
 
 #[async_trait]
-impl<'a> Migration for &'a (dyn Migration + Send + Sync) {
+impl Migration for &(dyn Migration + Send + Sync) {
     fn version(&self) -> &str {
-        (&**self).version()
+        (**self).version()
     }
 
     async fn run(&self, transaction: &DbTransaction<'_>) -> Result<(), Error> {
-        (&**self).run(transaction).await
+        (**self).run(transaction).await
     }
 
     async fn revert(&self, transaction: &DbTransaction<'_>) -> Result<(), Error> {
-        (&**self).revert(transaction).await
+        (**self).revert(transaction).await
     }
 
     fn file_path(&self) -> Option<&Path> {
-        (&**self).file_path()
+        (**self).file_path()
+    }
+
+    fn script_path(&self, sql_file: &str) -> Option<PathBuf> {
+        (**self).script_path(sql_file)
+    }
+
+    fn script_line_offset(&self, sql_file: &str) -> Result<usize, Error> {
+        (**self).script_line_offset(sql_file)
+    }
+
+    fn up_sql(&self) -> Result<Option<String>, Error> {
+        (**self).up_sql()
+    }
+
+    fn down_sql(&self) -> Result<Option<String>, Error> {
+        (**self).down_sql()
+    }
+
+    fn checksum(&self) -> Result<Option<String>, Error> {
+        (**self).checksum()
+    }
+
+    fn no_transaction(&self) -> Result<bool, Error> {
+        (**self).no_transaction()
+    }
+
+    fn irreversible(&self) -> Result<bool, Error> {
+        (**self).irreversible()
+    }
+
+    fn repeatable(&self) -> bool {
+        (**self).repeatable()
+    }
+
+    async fn run_without_transaction(&self, client: &Client) -> Result<(), Error> {
+        (**self).run_without_transaction(client).await
+    }
+
+    fn no_transaction_revert(&self) -> Result<bool, Error> {
+        (**self).no_transaction_revert()
+    }
+
+    async fn revert_without_transaction(&self, client: &Client) -> Result<(), Error> {
+        (**self).revert_without_transaction(client).await
     }
 }
 
@@ -61,7 +261,7 @@ pub struct MigrationName<'a> {
     pub migration: &'a dyn Migration,
 }
 
-pub fn name(migration: &dyn Migration) -> MigrationName {
+pub fn name(migration: &dyn Migration) -> MigrationName<'_> {
     MigrationName { migration }
 }
 
@@ -92,8 +292,7 @@ pub fn file_name<'a>(migration: &'a dyn Migration, sql_file: &'a str) -> Migrati
 
 impl<'a> fmt::Display for MigrationFileName<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let Some(path) = self.migration.file_path() {
-            let fpath = path.join(self.sql_file);
+        if let Some(fpath) = self.migration.script_path(self.sql_file) {
             f.write_str(fpath.to_str().unwrap_or("Invalid utf8 in filename"))
         } else {
             write!(f, "{}/{}", self.migration.version(), self.sql_file)
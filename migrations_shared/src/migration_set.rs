@@ -0,0 +1,83 @@
+use crate::connection::*;
+use crate::error::*;
+use crate::migration::*;
+use crate::observer::*;
+use crate::run_migrations_with_config;
+use crate::runner::*;
+use std::path::PathBuf;
+
+/// A list of migrations from different sources, e.g. the SQL migrations of a directory or of
+/// `embed_migrations!` together with migrations written in Rust.
+///
+/// ```ignore
+/// let migrations = MigrationSet::new()
+///     .with_migrations(migrations_in_directory(&dir)?)
+///     .with_migration(BackfillEmails)
+///     .into_migrations()?;
+/// run_migrations(pool, migrations, &WriteObserver::new(stdout())).await?;
+/// ```
+#[derive(Default)]
+pub struct MigrationSet {
+    migrations: Vec<Box<dyn Migration + Send + Sync>>,
+}
+
+impl MigrationSet {
+    pub fn new() -> MigrationSet {
+        MigrationSet::default()
+    }
+
+    pub fn with_migration<M>(mut self, migration: M) -> MigrationSet
+    where
+        M: Migration + Send + Sync + 'static,
+    {
+        self.migrations.push(Box::new(migration));
+        self
+    }
+
+    pub fn with_migrations<I, M>(mut self, migrations: I) -> MigrationSet
+    where
+        I: IntoIterator<Item = M>,
+        M: Migration + Send + Sync + 'static,
+    {
+        self.migrations.extend(
+            migrations
+                .into_iter()
+                .map(|m| Box::new(m) as Box<dyn Migration + Send + Sync>),
+        );
+        self
+    }
+
+    /// Returns the migrations sorted by version, or `Error::DuplicateMigrationVersion` if two of
+    /// them have the same version. The result can be passed to `run_migrations` and the other
+    /// functions taking a list of migrations.
+    pub fn into_migrations(mut self) -> Result<Vec<Box<dyn Migration + Send + Sync>>, Error> {
+        self.migrations.sort_by(|a, b| a.version().cmp(b.version()));
+        for pair in self.migrations.windows(2) {
+            if pair[0].version() == pair[1].version() {
+                return Err(Error::DuplicateMigrationVersion(
+                    pair[0].version().to_string(),
+                    migration_path(&pair[0]),
+                    migration_path(&pair[1]),
+                ));
+            }
+        }
+        Ok(self.migrations)
+    }
+
+    /// Runs all pending migrations of the set, see `run_migrations_with_config`.
+    pub async fn run<S: ConnectionSource>(
+        self,
+        source: S,
+        config: MigrationsConfig,
+        observer: &(dyn MigrationObserver + Sync),
+    ) -> Result<(), Error> {
+        run_migrations_with_config(source, self.into_migrations()?, config, observer).await
+    }
+}
+
+fn migration_path(migration: &(dyn Migration + Send + Sync)) -> PathBuf {
+    match migration.file_path() {
+        Some(path) => path.to_path_buf(),
+        None => PathBuf::from(name(&migration).to_string()),
+    }
+}
@@ -0,0 +1,251 @@
+use crate::error::*;
+use crate::migration::*;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Receives progress events while migrations are run or reverted. Every method does nothing by
+/// default. Returning an `Err` aborts the operation which triggered the event.
+///
+/// Observers are shared by reference, so migrations of several tenant schemas can report to the
+/// same observer concurrently.
+pub trait MigrationObserver {
+    /// A pending migration is about to run
+    fn started(&self, _migration: &dyn Migration) -> Result<(), Error> {
+        Ok(())
+    }
+    /// A migration has been applied and recorded, in the given time. When all pending migrations
+    /// run in a single transaction, this is reported before the transaction is committed.
+    fn succeeded(&self, _migration: &dyn Migration, _duration: Duration) -> Result<(), Error> {
+        Ok(())
+    }
+    /// A migration failed to run
+    fn failed(&self, _migration: &dyn Migration, _error: &Error) -> Result<(), Error> {
+        Ok(())
+    }
+    /// An applied migration is about to be reverted
+    fn reverting(&self, _migration: &dyn Migration) -> Result<(), Error> {
+        Ok(())
+    }
+    /// A migration has been reverted and removed from the migrations table, in the given time
+    fn reverted(&self, _migration: &dyn Migration, _duration: Duration) -> Result<(), Error> {
+        Ok(())
+    }
+    /// A migration failed to revert
+    fn revert_failed(&self, _migration: &dyn Migration, _error: &Error) -> Result<(), Error> {
+        Ok(())
+    }
+    /// A statement of a migration script is about to run, when statements are run one by one
+    fn statement_started(&self, _migration: &dyn Migration, _statement: &str) -> Result<(), Error> {
+        Ok(())
+    }
+    /// A statement of a migration script ran in the given time
+    fn statement_succeeded(
+        &self,
+        _migration: &dyn Migration,
+        _statement: &str,
+        _duration: Duration,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+    /// A migration is not run because it has already been applied
+    fn skipped(&self, _migration: &dyn Migration) -> Result<(), Error> {
+        Ok(())
+    }
+    /// The stored checksum of an applied migration is about to be replaced by its current one
+    fn checksum_repaired(&self, _migration: &dyn Migration) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Pending migrations older than the latest applied one are about to run
+    fn out_of_order(&self, _versions: &[String], _latest_version: &str) -> Result<(), Error> {
+        Ok(())
+    }
+    /// A pending migration would run with the given SQL, in dry-run mode. `sql` is `None` for
+    /// migrations not backed by an SQL script.
+    fn dry_run(&self, _migration: &dyn Migration, _sql: Option<&str>) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Migrations are about to be applied to the given tenant schema
+    fn schema_started(&self, _schema: &str) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Applying migrations to the given tenant schema failed
+    fn schema_failed(&self, _schema: &str, _error: &Error) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Writes progress as human readable lines, e.g. to stdout
+pub struct WriteObserver<W: Write> {
+    output: Mutex<W>,
+}
+
+impl<W: Write> WriteObserver<W> {
+    pub fn new(output: W) -> WriteObserver<W> {
+        WriteObserver {
+            output: Mutex::new(output),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write_line(&self, line: std::fmt::Arguments) -> Result<(), Error> {
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        writeln!(output, "{}", line)?;
+        Ok(())
+    }
+}
+
+impl<W: Write> MigrationObserver for WriteObserver<W> {
+    fn started(&self, migration: &dyn Migration) -> Result<(), Error> {
+        if migration.version() == "00000000000000" {
+            return Ok(());
+        }
+        self.write_line(format_args!("Running migration {}", name(migration)))
+    }
+
+    fn failed(&self, migration: &dyn Migration, _error: &Error) -> Result<(), Error> {
+        self.write_line(format_args!(
+            "Executing migration script {}",
+            file_name(migration, "up.sql")
+        ))
+    }
+
+    fn reverting(&self, migration: &dyn Migration) -> Result<(), Error> {
+        self.write_line(format_args!("Rolling back migration {}", name(migration)))
+    }
+
+    fn revert_failed(&self, migration: &dyn Migration, _error: &Error) -> Result<(), Error> {
+        self.write_line(format_args!(
+            "Executing migration script {}",
+            file_name(migration, "down.sql")
+        ))
+    }
+
+    fn checksum_repaired(&self, migration: &dyn Migration) -> Result<(), Error> {
+        self.write_line(format_args!("Repairing checksum of migration {}", name(migration)))
+    }
+
+    fn out_of_order(&self, versions: &[String], latest_version: &str) -> Result<(), Error> {
+        self.write_line(format_args!(
+            "Warning: pending migrations {} are older than the latest applied migration {}",
+            versions.join(", "),
+            latest_version
+        ))
+    }
+
+    fn dry_run(&self, migration: &dyn Migration, sql: Option<&str>) -> Result<(), Error> {
+        let sql = sql.map_or("-- (not an SQL migration)", str::trim_end);
+        self.write_line(format_args!("-- Migration {}\n{}\n", name(migration), sql))
+    }
+
+    fn schema_started(&self, schema: &str) -> Result<(), Error> {
+        self.write_line(format_args!("Migrating schema {}", schema))
+    }
+
+    fn schema_failed(&self, schema: &str, error: &Error) -> Result<(), Error> {
+        self.write_line(format_args!("Migrating schema {} failed: {}", schema, error))
+    }
+}
+
+/// Reports progress through the `log` crate: failures as errors, out-of-order migrations as
+/// warnings, statements, skipped migrations and dry-run SQL at debug level, and everything else as info.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogObserver;
+
+impl MigrationObserver for LogObserver {
+    fn started(&self, migration: &dyn Migration) -> Result<(), Error> {
+        log::info!("Running migration {}", name(migration));
+        Ok(())
+    }
+
+    fn succeeded(&self, migration: &dyn Migration, duration: Duration) -> Result<(), Error> {
+        log::info!("Applied migration {} in {:?}", name(migration), duration);
+        Ok(())
+    }
+
+    fn failed(&self, migration: &dyn Migration, error: &Error) -> Result<(), Error> {
+        log::error!(
+            "Migration {} failed ({}): {}",
+            name(migration),
+            file_name(migration, "up.sql"),
+            error
+        );
+        Ok(())
+    }
+
+    fn reverting(&self, migration: &dyn Migration) -> Result<(), Error> {
+        log::info!("Rolling back migration {}", name(migration));
+        Ok(())
+    }
+
+    fn reverted(&self, migration: &dyn Migration, duration: Duration) -> Result<(), Error> {
+        log::info!("Reverted migration {} in {:?}", name(migration), duration);
+        Ok(())
+    }
+
+    fn revert_failed(&self, migration: &dyn Migration, error: &Error) -> Result<(), Error> {
+        log::error!(
+            "Reverting migration {} failed ({}): {}",
+            name(migration),
+            file_name(migration, "down.sql"),
+            error
+        );
+        Ok(())
+    }
+
+    fn statement_started(&self, migration: &dyn Migration, statement: &str) -> Result<(), Error> {
+        log::debug!("Running statement of migration {}: {}", name(migration), statement);
+        Ok(())
+    }
+
+    fn statement_succeeded(
+        &self,
+        migration: &dyn Migration,
+        _statement: &str,
+        duration: Duration,
+    ) -> Result<(), Error> {
+        log::debug!("Statement of migration {} ran in {:?}", name(migration), duration);
+        Ok(())
+    }
+
+    fn skipped(&self, migration: &dyn Migration) -> Result<(), Error> {
+        log::debug!("Skipping already applied migration {}", name(migration));
+        Ok(())
+    }
+
+    fn checksum_repaired(&self, migration: &dyn Migration) -> Result<(), Error> {
+        log::info!("Repairing checksum of migration {}", name(migration));
+        Ok(())
+    }
+
+    fn out_of_order(&self, versions: &[String], latest_version: &str) -> Result<(), Error> {
+        log::warn!(
+            "Pending migrations {} are older than the latest applied migration {}",
+            versions.join(", "),
+            latest_version
+        );
+        Ok(())
+    }
+
+    fn dry_run(&self, migration: &dyn Migration, sql: Option<&str>) -> Result<(), Error> {
+        log::debug!(
+            "Migration {} would run:\n{}",
+            name(migration),
+            sql.unwrap_or("(not an SQL migration)")
+        );
+        Ok(())
+    }
+
+    fn schema_started(&self, schema: &str) -> Result<(), Error> {
+        log::info!("Migrating schema {}", schema);
+        Ok(())
+    }
+
+    fn schema_failed(&self, schema: &str, error: &Error) -> Result<(), Error> {
+        log::error!("Migrating schema {} failed: {}", schema, error);
+        Ok(())
+    }
+}
@@ -1,67 +1,477 @@
+use crate::connection::*;
 use crate::error::*;
+use crate::lock::*;
+use crate::status::*;
 use bb8_postgres::{
     bb8::{Pool, PooledConnection},
     tokio_postgres::Transaction,
     PostgresConnectionManager,
 };
-use std::collections::HashSet;
-use tokio_postgres::NoTls;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::time::Duration;
+use tokio_postgres::tls::{MakeTlsConnect, TlsConnect};
+use tokio_postgres::{Client, NoTls, Socket};
 
-/// Create table statement for the `__schema_migrations` used by the postgresql
+/// Version of this crate, recorded with every migration run
+const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Name of the table recording which migrations have been run, unless configured otherwise
+pub const DEFAULT_MIGRATIONS_TABLE: &str = "__schema_migrations";
+
+/// Length of the `version` column of the migrations table
+pub const MAX_VERSION_LENGTH: usize = 255;
+
+/// Create table statement for the `__schema_migrations` used by the postgresql. The table name is
+/// replaced by `MigrationsConfig::qualified_table_name` when setting up the database.
 pub const CREATE_MIGRATIONS_TABLE: &str = include_str!("setup_migration_table.sql");
 
-pub type DbConnectionPool = Pool<PostgresConnectionManager<NoTls>>;
-pub type DbPooledConnection<'a> = PooledConnection<'a, PostgresConnectionManager<NoTls>>;
+/// Statements adding the columns missing from a migrations table created by an older version of
+/// this crate. The table name is replaced like in `CREATE_MIGRATIONS_TABLE`.
+pub const UPGRADE_MIGRATIONS_TABLE: &str = include_str!("upgrade_migration_table.sql");
+
+/// Columns of an up to date migrations table
+const MIGRATIONS_TABLE_COLUMNS: &[&str] = &[
+    "version",
+    "run_on",
+    "checksum",
+    "name",
+    "execution_ms",
+    "applied_by",
+    "client_addr",
+    "application_name",
+    "crate_version",
+    "repeatable",
+];
+
+pub type DbConnectionPool<Tls = NoTls> = Pool<PostgresConnectionManager<Tls>>;
+pub type DbPooledConnection<'a, Tls = NoTls> = PooledConnection<'a, PostgresConnectionManager<Tls>>;
 pub type DbTransaction<'a> = Transaction<'a>;
 
-pub struct MigrationsRunner {
-    pool: DbConnectionPool,
+/// TLS connector usable by the connection pool, e.g. `NoTls` or
+/// `postgres_native_tls::MakeTlsConnector` for databases requiring encrypted connections.
+pub trait MakeTls:
+    MakeTlsConnect<Socket, Stream: Send + Sync, TlsConnect: TlsConnect<Socket, Future: Send> + Send>
+    + Clone
+    + Send
+    + Sync
+    + 'static
+{
+}
+
+impl<T> MakeTls for T where
+    T: MakeTlsConnect<Socket, Stream: Send + Sync, TlsConnect: TlsConnect<Socket, Future: Send> + Send>
+        + Clone
+        + Send
+        + Sync
+        + 'static
+{
+}
+
+/// What to do with pending migrations whose version is older than the latest applied migration,
+/// e.g. after merging a branch whose migrations were generated before the ones already deployed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutOfOrderPolicy {
+    /// Run them like any other pending migration
+    Allow,
+    /// Run them, after reporting them to the observer
+    Warn,
+    /// Run nothing and return `Error::OutOfOrderMigrations`
+    Reject,
+}
+
+/// Options controlling how `MigrationsRunner` applies migrations
+#[derive(Clone, Debug)]
+pub struct MigrationsConfig {
+    /// Advisory lock held while pending migrations are applied. Disabled when `None`.
+    pub lock: Option<AdvisoryLock>,
+    /// Overwrite the stored checksums of already applied migrations whose script changed,
+    /// instead of failing with `Error::ChecksumMismatch`.
+    pub repair_checksums: bool,
+    /// Report the name and SQL of each pending migration to the observer instead of running it.
+    /// The database is only read, and the lock is not taken.
+    pub dry_run: bool,
+    /// Apply all pending migrations in a single transaction, so either all of them or none are
+    /// committed. By default each migration runs in its own transaction.
+    pub single_transaction: bool,
+    /// Name of the table recording which migrations have been run
+    pub table_name: String,
+    /// Schema of the migrations table. When `None`, the table is looked up in the connection's
+    /// `search_path`. The schema is not created, it has to exist before migrations are run.
+    pub table_schema: Option<String>,
+    /// Schema set as the `search_path` while migrations are run or reverted, so their
+    /// unqualified names resolve to it. The connection's `search_path` is used when `None`.
+    pub search_path: Option<String>,
+    /// How pending migrations older than the latest applied migration are handled
+    pub out_of_order: OutOfOrderPolicy,
+    /// Run the scripts of SQL migrations statement by statement, reporting each one to the
+    /// observer, instead of sending whole scripts at once. Scripts containing `COPY ... FROM stdin`
+    /// data can only be run this way.
+    pub split_statements: bool,
+}
+
+impl Default for MigrationsConfig {
+    fn default() -> Self {
+        MigrationsConfig {
+            lock: Some(AdvisoryLock::default()),
+            repair_checksums: false,
+            dry_run: false,
+            single_transaction: false,
+            table_name: DEFAULT_MIGRATIONS_TABLE.to_string(),
+            table_schema: None,
+            search_path: None,
+            out_of_order: OutOfOrderPolicy::Allow,
+            split_statements: false,
+        }
+    }
+}
+
+impl MigrationsConfig {
+    /// The migrations table name quoted for use in queries, qualified with its schema if set
+    pub fn qualified_table_name(&self) -> String {
+        let table = quote_identifier(&self.table_name);
+        match &self.table_schema {
+            Some(schema) => format!("{}.{}", quote_identifier(schema), table),
+            None => table,
+        }
+    }
+}
+
+/// Length of a `character varying(<length>)` column type, as returned by `format_type`
+fn varchar_length(column_type: &str) -> Option<usize> {
+    column_type
+        .strip_prefix("character varying(")?
+        .strip_suffix(')')?
+        .parse()
+        .ok()
+}
+
+/// Condition selecting the versioned migrations of the migrations table with the given columns.
+/// Tables created before repeatable migrations existed only contain versioned ones.
+fn versioned_condition(columns: &HashMap<String, String>) -> &'static str {
+    if columns.contains_key("repeatable") {
+        "NOT repeatable"
+    } else {
+        "TRUE"
+    }
+}
+
+/// The given column of the migrations table with the given columns, or `NULL` of the given type
+/// for tables created before the column was added
+fn column_or_null(columns: &HashMap<String, String>, name: &str, sql_type: &str) -> String {
+    if columns.contains_key(name) {
+        name.to_string()
+    } else {
+        format!("NULL::{}", sql_type)
+    }
+}
+
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
 }
 
-impl MigrationsRunner {
-    pub fn from_pool(pool: DbConnectionPool) -> MigrationsRunner {
-        MigrationsRunner { pool }
+pub struct MigrationsRunner<S: ConnectionSource = DbConnectionPool> {
+    source: S,
+    config: MigrationsConfig,
+    table: String,
+}
+
+impl<S: ConnectionSource> MigrationsRunner<S> {
+    pub fn from_source(source: S) -> MigrationsRunner<S> {
+        MigrationsRunner::new(source, MigrationsConfig::default())
+    }
+
+    pub fn new(source: S, config: MigrationsConfig) -> MigrationsRunner<S> {
+        let table = config.qualified_table_name();
+        MigrationsRunner { source, config, table }
+    }
+
+    pub fn config(&self) -> &MigrationsConfig {
+        &self.config
+    }
+
+    pub async fn get_connection(&self) -> Result<DbConnection<'_>, Error> {
+        self.source.connection().await
+    }
+
+    /// Starts a transaction on the given connection, with the configured `search_path` set for
+    /// its duration
+    pub async fn transaction<'a>(&self, conn: &'a mut Client) -> Result<DbTransaction<'a>, Error> {
+        let transaction = conn.transaction().await?;
+        if let Some(schema) = &self.config.search_path {
+            let query = format!("SET LOCAL search_path TO {}", quote_identifier(schema));
+            transaction.batch_execute(&query).await?;
+        }
+        Ok(transaction)
+    }
+
+    /// Sets the configured `search_path` for the session of the given connection, until
+    /// `restore_search_path` is called with the returned previous value. Used for migrations run
+    /// outside of a transaction.
+    pub async fn set_search_path(&self, conn: &Client) -> Result<Option<String>, Error> {
+        let schema = match &self.config.search_path {
+            Some(schema) => schema,
+            None => return Ok(None),
+        };
+        let previous: String = conn.query_one("SHOW search_path", &[]).await?.try_get(0)?;
+        let query = format!("SET search_path TO {}", quote_identifier(schema));
+        conn.batch_execute(&query).await?;
+        Ok(Some(previous))
+    }
+
+    /// Restores the `search_path` returned by `set_search_path`
+    pub async fn restore_search_path(&self, conn: &Client, previous: Option<String>) -> Result<(), Error> {
+        if let Some(previous) = previous {
+            conn.execute("SELECT set_config('search_path', $1, false)", &[&previous])
+                .await?;
+        }
+        Ok(())
     }
 
-    pub async fn get_pooled_conn(&self) -> Result<DbPooledConnection<'_>, Error> {
-        let conn = self.pool.get().await?;
-        Ok(conn)
+    /// Takes the configured advisory lock, on a dedicated connection if the source provides one
+    /// so it does not hold a connection used to apply migrations. Returns `None` if locking is
+    /// disabled.
+    pub async fn acquire_lock(&self) -> Result<Option<MigrationsLock<'_, S>>, Error> {
+        match &self.config.lock {
+            Some(lock) => Ok(Some(lock.acquire(&self.source).await?)),
+            None => Ok(None),
+        }
     }
 
+    /// Returns the names of the schemas matching the given `LIKE` pattern, in alphabetical order
+    pub async fn schemas_like(&self, pattern: &str) -> Result<Vec<String>, Error> {
+        let conn = self.source.connection().await?;
+        let query = "SELECT schema_name::TEXT FROM information_schema.schemata WHERE schema_name LIKE $1 \
+                     ORDER BY schema_name";
+        let rows = conn.query(query, &[&pattern]).await?;
+        rows.iter().map(|row| Ok(row.try_get(0)?)).collect()
+    }
+
+    /// Creates the migrations table, or upgrades a table created by an older version of this
+    /// crate: missing columns are added and the `version` column is widened to
+    /// `MAX_VERSION_LENGTH`. An up to date table is left alone, so no lock is taken on it.
     pub async fn setup_database(&self) -> Result<(), Error> {
-        let conn = self.pool.get().await?;
-        conn.execute(CREATE_MIGRATIONS_TABLE, &[]).await?;
+        let conn = self.source.connection().await?;
+        let columns = self.table_columns(&conn).await?;
+        if columns.is_empty() {
+            let query = CREATE_MIGRATIONS_TABLE.replace(DEFAULT_MIGRATIONS_TABLE, &self.table);
+            conn.batch_execute(&query).await?;
+            return Ok(());
+        }
+        if !MIGRATIONS_TABLE_COLUMNS.iter().all(|c| columns.contains_key(*c)) {
+            let query = UPGRADE_MIGRATIONS_TABLE.replace(DEFAULT_MIGRATIONS_TABLE, &self.table);
+            conn.batch_execute(&query).await?;
+        }
+        let version_length = columns.get("version").and_then(|t| varchar_length(t));
+        if version_length.is_some_and(|length| length < MAX_VERSION_LENGTH) {
+            let query = format!(
+                "ALTER TABLE {} ALTER COLUMN version TYPE VARCHAR({})",
+                self.table, MAX_VERSION_LENGTH
+            );
+            conn.batch_execute(&query).await?;
+        }
         Ok(())
     }
 
+    /// Returns the type of each column of the migrations table, by name. The map is empty if the
+    /// table does not exist yet.
+    async fn table_columns(&self, conn: &Client) -> Result<HashMap<String, String>, Error> {
+        let query = "SELECT attname::TEXT, format_type(atttypid, atttypmod) FROM pg_attribute \
+                     WHERE attrelid = to_regclass($1) AND attnum > 0 AND NOT attisdropped";
+        let rows = conn.query(query, &[&self.table]).await?;
+        rows.iter().map(|row| Ok((row.try_get(0)?, row.try_get(1)?))).collect()
+    }
+
     pub async fn previously_run_migration_versions(&self) -> Result<HashSet<String>, Error> {
-        let conn = self.pool.get().await?;
+        let conn = self.source.connection().await?;
         let mut migrations = HashSet::new();
-        let query = "SELECT version FROM __schema_migrations";
-        for row in &conn.query(query, &[]).await? {
+        let columns = self.table_columns(&conn).await?;
+        let query = format!(
+            "SELECT version FROM {} WHERE {}",
+            self.table,
+            versioned_condition(&columns)
+        );
+        for row in &conn.query(query.as_str(), &[]).await? {
             migrations.insert(row.try_get(0)?);
         }
         Ok(migrations)
     }
 
-    pub async fn latest_run_migration_version(&self) -> Result<Option<String>, Error> {
-        let conn = self.pool.get().await?;
-        let query = "SELECT MAX(version) FROM __schema_migrations";
-        if let Some(row) = &conn.query_opt(query, &[]).await? {
-            return Ok(Some(row.try_get(0)?));
+    /// Returns the checksum stored for each previously run migration, repeatable migrations
+    /// excepted. Migrations run before checksums were tracked have no checksum. Does not require
+    /// `setup_database` to have been called: nothing has been run if the migrations table does not
+    /// exist yet.
+    pub async fn previously_run_migration_checksums(&self) -> Result<HashMap<String, Option<String>>, Error> {
+        let conn = self.source.connection().await?;
+        let columns = self.table_columns(&conn).await?;
+        if columns.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let query = format!(
+            "SELECT version, {} FROM {} WHERE {}",
+            column_or_null(&columns, "checksum", "VARCHAR"),
+            self.table,
+            versioned_condition(&columns)
+        );
+        let rows = conn.query(query.as_str(), &[]).await?;
+        rows.iter().map(|row| Ok((row.try_get(0)?, row.try_get(1)?))).collect()
+    }
+
+    /// Returns the checksum recorded when each repeatable migration last ran, by name. Like
+    /// `previously_run_migration_checksums`, does not require `setup_database` to have been called.
+    pub async fn repeatable_migration_checksums(&self) -> Result<HashMap<String, Option<String>>, Error> {
+        let conn = self.source.connection().await?;
+        let columns = self.table_columns(&conn).await?;
+        // Tables created before repeatable migrations existed contain none
+        if !columns.contains_key("repeatable") {
+            return Ok(HashMap::new());
+        }
+        let query = format!("SELECT version, checksum FROM {} WHERE repeatable", self.table);
+        let rows = conn.query(query.as_str(), &[]).await?;
+        rows.iter().map(|row| Ok((row.try_get(0)?, row.try_get(1)?))).collect()
+    }
+
+    /// Returns every previously run migration ordered by version, repeatable migrations excepted.
+    /// Like `previously_run_migration_checksums`, does not require `setup_database` to have been
+    /// called: columns missing from a table created by an older version are read as `None`.
+    pub async fn applied_migrations(&self) -> Result<Vec<AppliedMigration>, Error> {
+        let conn = self.source.connection().await?;
+        let columns = self.table_columns(&conn).await?;
+        if columns.is_empty() {
+            return Ok(Vec::new());
+        }
+        let query = format!(
+            "SELECT version, run_on, {}, {}, {}, {}, {}, {}, {} FROM {} WHERE {} ORDER BY version",
+            column_or_null(&columns, "checksum", "VARCHAR"),
+            column_or_null(&columns, "name", "VARCHAR"),
+            column_or_null(&columns, "execution_ms", "BIGINT"),
+            column_or_null(&columns, "applied_by", "VARCHAR"),
+            column_or_null(&columns, "client_addr", "VARCHAR"),
+            column_or_null(&columns, "application_name", "VARCHAR"),
+            column_or_null(&columns, "crate_version", "VARCHAR"),
+            self.table,
+            versioned_condition(&columns)
+        );
+        let mut migrations = Vec::new();
+        for row in &conn.query(query.as_str(), &[]).await? {
+            migrations.push(AppliedMigration {
+                version: row.try_get(0)?,
+                run_on: row.try_get(1)?,
+                checksum: row.try_get(2)?,
+                name: row.try_get(3)?,
+                execution_ms: row.try_get(4)?,
+                applied_by: row.try_get(5)?,
+                client_addr: row.try_get(6)?,
+                application_name: row.try_get(7)?,
+                crate_version: row.try_get(8)?,
+            });
         }
-        Ok(None)
+        Ok(migrations)
+    }
+
+    /// Returns the versions of the `count` latest run migrations, newest first
+    pub async fn latest_run_migration_versions(&self, count: i64) -> Result<Vec<String>, Error> {
+        let conn = self.source.connection().await?;
+        let columns = self.table_columns(&conn).await?;
+        let query = format!(
+            "SELECT version FROM {} WHERE {} ORDER BY version DESC LIMIT $1",
+            self.table,
+            versioned_condition(&columns)
+        );
+        let rows = conn.query(query.as_str(), &[&count]).await?;
+        rows.iter().map(|row| Ok(row.try_get(0)?)).collect()
+    }
+
+    /// Returns the versions of the run migrations later than `ver`, newest first
+    pub async fn run_migration_versions_after(&self, ver: &str) -> Result<Vec<String>, Error> {
+        let conn = self.source.connection().await?;
+        let columns = self.table_columns(&conn).await?;
+        let query = format!(
+            "SELECT version FROM {} WHERE {} AND version > $1 ORDER BY version DESC",
+            self.table,
+            versioned_condition(&columns)
+        );
+        let rows = conn.query(query.as_str(), &[&ver]).await?;
+        rows.iter().map(|row| Ok(row.try_get(0)?)).collect()
+    }
+
+    pub async fn latest_run_migration_version(&self) -> Result<Option<String>, Error> {
+        let conn = self.source.connection().await?;
+        let columns = self.table_columns(&conn).await?;
+        let query = format!(
+            "SELECT MAX(version) FROM {} WHERE {}",
+            self.table,
+            versioned_condition(&columns)
+        );
+        let row = conn.query_one(query.as_str(), &[]).await?;
+        Ok(row.try_get(0)?)
+    }
+
+    /// Records a migration as run. Besides the given values, the database user, client address and
+    /// application name of the session, and the version of this crate are stored.
+    pub async fn insert_new_migration(
+        &self,
+        transaction: &DbTransaction<'_>,
+        ver: &str,
+        name: &str,
+        checksum: Option<&str>,
+        execution_time: Duration,
+    ) -> Result<(), Error> {
+        let query = format!(
+            "INSERT INTO {} (version, name, checksum, execution_ms, applied_by, client_addr, \
+             application_name, crate_version) \
+             VALUES($1, $2, $3, $4, CURRENT_USER, HOST(INET_CLIENT_ADDR()), \
+             CURRENT_SETTING('application_name'), $5)",
+            self.table
+        );
+        let execution_ms = i64::try_from(execution_time.as_millis()).unwrap_or(i64::MAX);
+        transaction
+            .execute(query.as_str(), &[&ver, &name, &checksum, &execution_ms, &CRATE_VERSION])
+            .await?;
+        Ok(())
+    }
+
+    /// Records a repeatable migration as run, replacing what was recorded when it last ran
+    pub async fn record_repeatable_migration(
+        &self,
+        transaction: &DbTransaction<'_>,
+        name: &str,
+        display_name: &str,
+        checksum: Option<&str>,
+        execution_time: Duration,
+    ) -> Result<(), Error> {
+        let query = format!(
+            "INSERT INTO {0} AS m (version, name, checksum, execution_ms, applied_by, client_addr, \
+             application_name, crate_version, repeatable) \
+             VALUES($1, $2, $3, $4, CURRENT_USER, HOST(INET_CLIENT_ADDR()), \
+             CURRENT_SETTING('application_name'), $5, TRUE) \
+             ON CONFLICT (version) DO UPDATE SET run_on=CURRENT_TIMESTAMP, name=EXCLUDED.name, \
+             checksum=EXCLUDED.checksum, execution_ms=EXCLUDED.execution_ms, \
+             applied_by=EXCLUDED.applied_by, client_addr=EXCLUDED.client_addr, \
+             application_name=EXCLUDED.application_name, crate_version=EXCLUDED.crate_version \
+             WHERE m.repeatable",
+            self.table
+        );
+        let execution_ms = i64::try_from(execution_time.as_millis()).unwrap_or(i64::MAX);
+        transaction
+            .execute(
+                query.as_str(),
+                &[&name, &display_name, &checksum, &execution_ms, &CRATE_VERSION],
+            )
+            .await?;
+        Ok(())
     }
 
-    pub async fn insert_new_migration(&self, transaction: &DbTransaction<'_>, ver: &str) -> Result<(), Error> {
-        let query = "INSERT INTO __schema_migrations VALUES($1)";
-        transaction.execute(query, &[&ver]).await?;
+    pub async fn update_migration_checksum(&self, ver: &str, checksum: Option<&str>) -> Result<(), Error> {
+        let conn = self.source.connection().await?;
+        let query = format!("UPDATE {} SET checksum=$2 WHERE version=$1", self.table);
+        conn.execute(query.as_str(), &[&ver, &checksum]).await?;
         Ok(())
     }
 
     pub async fn delete_migration(&self, transaction: &DbTransaction<'_>, ver: &str) -> Result<(), Error> {
-        let query = "DELETE FROM __schema_migrations WHERE version=$1";
-        transaction.execute(query, &[&ver]).await?;
+        let query = format!("DELETE FROM {} WHERE version=$1", self.table);
+        transaction.execute(query.as_str(), &[&ver]).await?;
         Ok(())
     }
 }
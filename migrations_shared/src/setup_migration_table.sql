@@ -1,4 +1,12 @@
 CREATE TABLE IF NOT EXISTS __schema_migrations (
-       version VARCHAR(50) PRIMARY KEY NOT NULL,
-       run_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+       version VARCHAR(255) PRIMARY KEY NOT NULL,
+       run_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+       checksum VARCHAR(32),
+       name VARCHAR(255),
+       execution_ms BIGINT,
+       applied_by VARCHAR(63),
+       client_addr VARCHAR(45),
+       application_name VARCHAR(63),
+       crate_version VARCHAR(32),
+       repeatable BOOLEAN NOT NULL DEFAULT FALSE
 );
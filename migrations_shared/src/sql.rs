@@ -0,0 +1,349 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{pin_mut, SinkExt};
+use tokio_postgres::{Client, Transaction};
+
+/// A statement of an SQL script, as returned by `split_statements`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SqlStatement<'a> {
+    /// The statement, including its terminating semicolon if it has one. Comments before the
+    /// statement are not included.
+    pub sql: &'a str,
+    /// Byte offset of the statement in the script
+    pub offset: usize,
+    /// The data following a `COPY ... FROM stdin` statement, up to the `\.` line ending it
+    pub copy_data: Option<&'a str>,
+}
+
+/// Splits an SQL script into its statements. Semicolons inside quoted strings and identifiers,
+/// dollar-quoted strings such as function bodies, and comments do not end a statement. Statements
+/// consisting only of comments are skipped.
+pub fn split_statements(script: &str) -> Vec<SqlStatement<'_>> {
+    let bytes = script.as_bytes();
+    let mut statements = Vec::new();
+    let mut start = None;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let next = bytes.get(i + 1).copied();
+        if c == b'-' && next == Some(b'-') {
+            i = script[i..].find('\n').map_or(bytes.len(), |end| i + end + 1);
+            continue;
+        }
+        if c == b'/' && next == Some(b'*') {
+            i = block_comment_end(bytes, i);
+            continue;
+        }
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let statement_start = *start.get_or_insert(i);
+        i = match c {
+            b'\'' => {
+                let escapes = i > 0 && matches!(bytes[i - 1], b'e' | b'E') && !follows_identifier_char(bytes, i - 1);
+                quoted_end(bytes, i, b'\'', escapes)
+            }
+            b'"' => quoted_end(bytes, i, b'"', false),
+            b'$' => match dollar_tag(script, i) {
+                Some(tag) => script[i + tag.len()..]
+                    .find(tag)
+                    .map_or(bytes.len(), |end| i + tag.len() + end + tag.len()),
+                None => i + 1,
+            },
+            b';' => {
+                let sql = &script[statement_start..=i];
+                start = None;
+                let mut end = i + 1;
+                let copy_data = if is_copy_from_stdin(sql) {
+                    let (data, data_end) = copy_data(script, end);
+                    end = data_end;
+                    Some(data)
+                } else {
+                    None
+                };
+                statements.push(SqlStatement {
+                    sql,
+                    offset: statement_start,
+                    copy_data,
+                });
+                end
+            }
+            _ => i + 1,
+        };
+    }
+    if let Some(statement_start) = start {
+        statements.push(SqlStatement {
+            sql: script[statement_start..].trim_end(),
+            offset: statement_start,
+            copy_data: None,
+        });
+    }
+    statements
+}
+
+fn follows_identifier_char(bytes: &[u8], i: usize) -> bool {
+    i > 0 && (bytes[i - 1].is_ascii_alphanumeric() || bytes[i - 1] == b'_' || bytes[i - 1] >= 0x80)
+}
+
+/// Returns the index after the end of the (possibly nested) block comment starting at `i`
+fn block_comment_end(bytes: &[u8], mut i: usize) -> usize {
+    let mut depth = 0;
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1).copied()) {
+            (b'/', Some(b'*')) => {
+                depth += 1;
+                i += 2;
+            }
+            (b'*', Some(b'/')) => {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    return i;
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// Returns the index after the closing quote of the string or identifier starting at `i`. A
+/// doubled quote does not close it, nor does a quote escaped by a backslash if `escapes` is set.
+fn quoted_end(bytes: &[u8], mut i: usize, quote: u8, escapes: bool) -> usize {
+    i += 1;
+    while i < bytes.len() {
+        if escapes && bytes[i] == b'\\' {
+            i += 2;
+        } else if bytes[i] == quote {
+            if bytes.get(i + 1) == Some(&quote) {
+                i += 2;
+            } else {
+                return i + 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    bytes.len()
+}
+
+/// Returns the `$tag$` starting at `i`, if it opens a dollar-quoted string rather than being a
+/// parameter such as `$1` or part of an identifier
+fn dollar_tag(script: &str, i: usize) -> Option<&str> {
+    let bytes = script.as_bytes();
+    if follows_identifier_char(bytes, i) {
+        return None;
+    }
+    let tag_len = script[i + 1..].find('$')?;
+    let tag = &script[i + 1..i + 1 + tag_len];
+    let valid = tag
+        .chars()
+        .enumerate()
+        .all(|(n, c)| c == '_' || c.is_alphabetic() || (n > 0 && c.is_ascii_digit()));
+    if valid {
+        Some(&script[i..i + tag_len + 2])
+    } else {
+        None
+    }
+}
+
+fn is_copy_from_stdin(sql: &str) -> bool {
+    let words: Vec<_> = sql
+        .trim_end_matches(';')
+        .split_whitespace()
+        .map(str::to_ascii_uppercase)
+        .collect();
+    words.first().map(String::as_str) == Some("COPY")
+        && words.windows(2).any(|w| w[0] == "FROM" && w[1].starts_with("STDIN"))
+}
+
+/// Returns the data of a `COPY ... FROM stdin` statement whose semicolon ends at `start`, and the
+/// index after the `\.` line terminating it
+fn copy_data(script: &str, start: usize) -> (&str, usize) {
+    let data_start = script[start..].find('\n').map_or(script.len(), |end| start + end + 1);
+    let mut line_start = data_start;
+    while line_start < script.len() {
+        let line_end = script[line_start..]
+            .find('\n')
+            .map_or(script.len(), |end| line_start + end);
+        if script[line_start..line_end].trim_end_matches('\r') == "\\." {
+            let next = (line_end + 1).min(script.len());
+            return (&script[data_start..line_start], next);
+        }
+        line_start = line_end + 1;
+    }
+    (&script[data_start..], script.len())
+}
+
+/// Connection or transaction the statements of a script can be executed on
+#[async_trait]
+pub(crate) trait StatementExecutor: Sync {
+    async fn batch_execute(&self, sql: &str) -> Result<(), tokio_postgres::Error>;
+    async fn copy_in(&self, sql: &str, data: &str) -> Result<(), tokio_postgres::Error>;
+}
+
+#[async_trait]
+impl StatementExecutor for Client {
+    async fn batch_execute(&self, sql: &str) -> Result<(), tokio_postgres::Error> {
+        Client::batch_execute(self, sql).await
+    }
+
+    async fn copy_in(&self, sql: &str, data: &str) -> Result<(), tokio_postgres::Error> {
+        let sink = Client::copy_in(self, sql).await?;
+        pin_mut!(sink);
+        sink.send(Bytes::from(data.to_string())).await?;
+        sink.finish().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StatementExecutor for Transaction<'_> {
+    async fn batch_execute(&self, sql: &str) -> Result<(), tokio_postgres::Error> {
+        Transaction::batch_execute(self, sql).await
+    }
+
+    async fn copy_in(&self, sql: &str, data: &str) -> Result<(), tokio_postgres::Error> {
+        let sink = Transaction::copy_in(self, sql).await?;
+        pin_mut!(sink);
+        sink.send(Bytes::from(data.to_string())).await?;
+        sink.finish().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statements(script: &str) -> Vec<&str> {
+        split_statements(script).into_iter().map(|s| s.sql).collect()
+    }
+
+    #[test]
+    fn splits_on_semicolons_outside_of_literals_and_comments() {
+        let cases: &[(&str, &[&str])] = &[
+            ("SELECT 1; SELECT 2;", &["SELECT 1;", "SELECT 2;"]),
+            ("SELECT 1;\nSELECT 2\n\n", &["SELECT 1;", "SELECT 2"]),
+            ("", &[]),
+            // Quoted strings and identifiers
+            ("SELECT 'a;b'; SELECT 2;", &["SELECT 'a;b';", "SELECT 2;"]),
+            ("SELECT 'it''s;'; SELECT 2;", &["SELECT 'it''s;';", "SELECT 2;"]),
+            (
+                "SELECT \"a;b\" FROM t; SELECT 2;",
+                &["SELECT \"a;b\" FROM t;", "SELECT 2;"],
+            ),
+            // A backslash only escapes a quote in E'' strings
+            ("SELECT E'\\';'; SELECT 2;", &["SELECT E'\\';';", "SELECT 2;"]),
+            ("SELECT e'\\\\'; SELECT 2;", &["SELECT e'\\\\';", "SELECT 2;"]),
+            ("SELECT '\\'; SELECT 2;", &["SELECT '\\';", "SELECT 2;"]),
+            ("SELECT some'\\'; SELECT 2;", &["SELECT some'\\';", "SELECT 2;"]),
+            // Dollar-quoted strings
+            (
+                "CREATE FUNCTION f() RETURNS INT AS $$ SELECT 1; $$ LANGUAGE sql; SELECT 2;",
+                &[
+                    "CREATE FUNCTION f() RETURNS INT AS $$ SELECT 1; $$ LANGUAGE sql;",
+                    "SELECT 2;",
+                ],
+            ),
+            (
+                "DO $body$ BEGIN PERFORM 1; END $body$; SELECT 2;",
+                &["DO $body$ BEGIN PERFORM 1; END $body$;", "SELECT 2;"],
+            ),
+            (
+                "SELECT $a$ $b$ ; $b$ ; $a$; SELECT 2;",
+                &["SELECT $a$ $b$ ; $b$ ; $a$;", "SELECT 2;"],
+            ),
+            ("SELECT $_1$;$_1$; SELECT 2;", &["SELECT $_1$;$_1$;", "SELECT 2;"]),
+            // Parameters and identifiers containing `$` are not dollar quotes
+            (
+                "PREPARE p AS SELECT $1; SELECT $$x$$;",
+                &["PREPARE p AS SELECT $1;", "SELECT $$x$$;"],
+            ),
+            ("SELECT a$b; SELECT 2;", &["SELECT a$b;", "SELECT 2;"]),
+            // Comments
+            ("SELECT 1; -- no; split\nSELECT 2;", &["SELECT 1;", "SELECT 2;"]),
+            ("SELECT -- ;\n1; SELECT 2;", &["SELECT -- ;\n1;", "SELECT 2;"]),
+            (
+                "SELECT /* a /* b; */ c; */ 1; SELECT 2;",
+                &["SELECT /* a /* b; */ c; */ 1;", "SELECT 2;"],
+            ),
+            ("/* a; */ SELECT 1; /* b; */", &["SELECT 1;"]),
+            ("-- only a comment;\n/* and a block; */\n", &[]),
+            // Unterminated literals and comments run to the end of the script
+            ("SELECT 'abc; SELECT 2;", &["SELECT 'abc; SELECT 2;"]),
+            ("SELECT \"abc; SELECT 2;", &["SELECT \"abc; SELECT 2;"]),
+            ("SELECT E'abc\\'; SELECT 2;", &["SELECT E'abc\\'; SELECT 2;"]),
+            ("SELECT $$ abc; SELECT 2;", &["SELECT $$ abc; SELECT 2;"]),
+            ("SELECT /* abc; SELECT 2;", &["SELECT /* abc; SELECT 2;"]),
+            (
+                "SELECT /* a /* b */ c; SELECT 2;",
+                &["SELECT /* a /* b */ c; SELECT 2;"],
+            ),
+            ("SELECT 1; -- abc; SELECT 2;", &["SELECT 1;"]),
+            // Multibyte text
+            ("SELECT 'é;ü'; SELECT 'ü' AS ü;", &["SELECT 'é;ü';", "SELECT 'ü' AS ü;"]),
+            ("SELECT $é$ ; $é$; SELECT 2;", &["SELECT $é$ ; $é$;", "SELECT 2;"]),
+            ("SELECT \"é;\"; /* ü; */ SELECT 2;", &["SELECT \"é;\";", "SELECT 2;"]),
+        ];
+        for (script, expected) in cases {
+            assert_eq!(statements(script), *expected, "{:?}", script);
+        }
+    }
+
+    #[test]
+    fn returns_the_offset_of_each_statement() {
+        let script = "-- header\nSELECT 1;\n  SELECT 'é';\tSELECT 3";
+        let offsets: Vec<_> = split_statements(script).iter().map(|s| s.offset).collect();
+        assert_eq!(offsets, [10, 22, 35]);
+        for statement in split_statements(script) {
+            assert!(script[statement.offset..].starts_with(statement.sql));
+        }
+    }
+
+    /// A statement and its `COPY` data
+    type CopyStatement<'a> = (&'a str, Option<&'a str>);
+
+    #[test]
+    fn reads_copy_data_up_to_the_terminator() {
+        let cases: &[(&str, &[CopyStatement])] = &[
+            (
+                "COPY t (a) FROM stdin;\n1\n2\n\\.\nSELECT 2;",
+                &[("COPY t (a) FROM stdin;", Some("1\n2\n")), ("SELECT 2;", None)],
+            ),
+            // Semicolons, quotes and comment markers in the data are not SQL
+            (
+                "copy t from STDIN with (format csv);\na;'b\n-- c\n\\.\nSELECT 2;",
+                &[
+                    ("copy t from STDIN with (format csv);", Some("a;'b\n-- c\n")),
+                    ("SELECT 2;", None),
+                ],
+            ),
+            (
+                "COPY t FROM stdin;\r\n1\r\n\\.\r\nSELECT 2;",
+                &[("COPY t FROM stdin;", Some("1\r\n")), ("SELECT 2;", None)],
+            ),
+            ("COPY t FROM stdin;\n\\.\n", &[("COPY t FROM stdin;", Some(""))]),
+            // Unterminated data runs to the end of the script
+            ("COPY t FROM stdin;\n1\n2", &[("COPY t FROM stdin;", Some("1\n2"))]),
+            // Only COPY ... FROM stdin is followed by data
+            (
+                "COPY t TO stdout;\nSELECT 2;",
+                &[("COPY t TO stdout;", None), ("SELECT 2;", None)],
+            ),
+            (
+                "COPY t FROM '/tmp/t.csv';\nSELECT 2;",
+                &[("COPY t FROM '/tmp/t.csv';", None), ("SELECT 2;", None)],
+            ),
+        ];
+        for (script, expected) in cases {
+            let statements: Vec<_> = split_statements(script)
+                .into_iter()
+                .map(|s| (s.sql, s.copy_data))
+                .collect();
+            assert_eq!(statements, *expected, "{:?}", script);
+        }
+    }
+}
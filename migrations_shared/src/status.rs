@@ -0,0 +1,48 @@
+use std::time::SystemTime;
+
+/// A row of the `__schema_migrations` table recording a versioned migration
+#[derive(Clone, Debug)]
+pub struct AppliedMigration {
+    pub version: String,
+    pub run_on: SystemTime,
+    pub checksum: Option<String>,
+    /// Name of the migration, e.g. its directory name. The following columns are `None` for
+    /// migrations run before they were recorded.
+    pub name: Option<String>,
+    /// Time the migration took to run, in milliseconds
+    pub execution_ms: Option<i64>,
+    /// Database user which ran the migration
+    pub applied_by: Option<String>,
+    /// Address of the client which ran the migration, `None` for Unix socket connections
+    pub client_addr: Option<String>,
+    /// `application_name` of the session which ran the migration
+    pub application_name: Option<String>,
+    /// Version of this crate used to run the migration
+    pub crate_version: Option<String>,
+}
+
+/// Comparison between a list of migrations and the migrations recorded in the database,
+/// as returned by `migration_status`
+#[derive(Clone, Debug, Default)]
+pub struct MigrationStatus {
+    /// Applied migrations that are present in the given list, oldest first
+    pub applied: Vec<AppliedMigration>,
+    /// Versions of the migrations that have not been run yet, in the order they would be run
+    pub pending: Vec<String>,
+    /// Applied migrations that are not present in the given list
+    pub missing: Vec<AppliedMigration>,
+    /// Versions of applied migrations whose script no longer matches the stored checksum
+    pub checksum_mismatches: Vec<String>,
+    /// Names of the repeatable migrations that have not been run yet, or changed since they last ran
+    pub pending_repeatable: Vec<String>,
+}
+
+impl MigrationStatus {
+    /// Returns true if there is nothing to run and every applied migration matches its source
+    pub fn is_up_to_date(&self) -> bool {
+        self.pending.is_empty()
+            && self.missing.is_empty()
+            && self.checksum_mismatches.is_empty()
+            && self.pending_repeatable.is_empty()
+    }
+}
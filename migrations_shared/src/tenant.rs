@@ -0,0 +1,101 @@
+use crate::connection::*;
+use crate::error::*;
+use crate::migration::*;
+use crate::observer::*;
+use crate::run_migrations_with_config;
+use crate::runner::*;
+use futures::stream::{self, StreamExt};
+
+/// Schemas to apply migrations to with `run_tenant_migrations`
+#[derive(Clone, Debug)]
+pub enum TenantSchemas {
+    /// The given schemas, in this order
+    List(Vec<String>),
+    /// Every schema whose name matches the given `LIKE` pattern, in alphabetical order
+    Like(String),
+}
+
+/// Outcome of applying migrations to one schema
+#[derive(Debug)]
+pub struct TenantReport {
+    pub schema: String,
+    pub result: Result<(), Error>,
+}
+
+/// Runs all pending migrations in each of the given schemas. Every schema keeps its own
+/// migrations table, in `config.table_schema`'s place, and its migrations run with the schema as
+/// `search_path`.
+///
+/// Up to `parallelism` schemas are migrated at once; it should not exceed the number of
+/// connections the source can hand out, and the events of schemas migrated at the same time are
+/// interleaved. A failure in one schema does not stop the others, it is reported to the observer
+/// and in the returned list. When `config.lock` is set, the lock is held until every schema
+/// has been migrated.
+pub async fn run_tenant_migrations<T, S>(
+    source: S,
+    migrations: Vec<T>,
+    schemas: TenantSchemas,
+    config: MigrationsConfig,
+    parallelism: usize,
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<Vec<TenantReport>, Error>
+where
+    T: Migration + Send + Sync,
+    S: ConnectionSource,
+{
+    let runner = MigrationsRunner::new(&source, config.clone());
+    let schemas = match schemas {
+        TenantSchemas::List(schemas) => schemas,
+        TenantSchemas::Like(pattern) => runner.schemas_like(&pattern).await?,
+    };
+    if config.dry_run {
+        return migrate_schemas(&source, &migrations, schemas, config, parallelism, observer).await;
+    }
+    let lock = runner.acquire_lock().await?;
+    let result = migrate_schemas(&source, &migrations, schemas, config, parallelism, observer).await;
+    let released = match lock {
+        Some(lock) => lock.release().await,
+        None => Ok(()),
+    };
+    // A failure to release the lock is only reported if it is the only error
+    result.and_then(|reports| released.map(|()| reports))
+}
+
+async fn migrate_schemas<T, S>(
+    source: &S,
+    migrations: &[T],
+    schemas: Vec<String>,
+    config: MigrationsConfig,
+    parallelism: usize,
+    observer: &(dyn MigrationObserver + Sync),
+) -> Result<Vec<TenantReport>, Error>
+where
+    T: Migration + Send + Sync,
+    S: ConnectionSource,
+{
+    let mut tenants = stream::iter(schemas)
+        .map(|schema| {
+            let config = MigrationsConfig {
+                lock: None,
+                table_schema: Some(schema.clone()),
+                search_path: Some(schema.clone()),
+                ..config.clone()
+            };
+            async move {
+                observer.schema_started(&schema)?;
+                let migrations = migrations.iter().map(|m| m as &(dyn Migration + Send + Sync)).collect();
+                let result = run_migrations_with_config(source, migrations, config, observer).await;
+                if let Err(e) = &result {
+                    observer.schema_failed(&schema, e)?;
+                }
+                Ok::<_, Error>(TenantReport { schema, result })
+            }
+        })
+        .buffered(parallelism.max(1));
+
+    let mut reports = Vec::new();
+    while let Some(report) = tenants.next().await {
+        reports.push(report?);
+    }
+    Ok(reports)
+}
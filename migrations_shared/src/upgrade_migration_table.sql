@@ -0,0 +1,8 @@
+ALTER TABLE __schema_migrations ADD COLUMN IF NOT EXISTS checksum VARCHAR(32);
+ALTER TABLE __schema_migrations ADD COLUMN IF NOT EXISTS name VARCHAR(255);
+ALTER TABLE __schema_migrations ADD COLUMN IF NOT EXISTS execution_ms BIGINT;
+ALTER TABLE __schema_migrations ADD COLUMN IF NOT EXISTS applied_by VARCHAR(63);
+ALTER TABLE __schema_migrations ADD COLUMN IF NOT EXISTS client_addr VARCHAR(45);
+ALTER TABLE __schema_migrations ADD COLUMN IF NOT EXISTS application_name VARCHAR(63);
+ALTER TABLE __schema_migrations ADD COLUMN IF NOT EXISTS crate_version VARCHAR(32);
+ALTER TABLE __schema_migrations ADD COLUMN IF NOT EXISTS repeatable BOOLEAN NOT NULL DEFAULT FALSE;
@@ -10,6 +10,10 @@ pub use migrations_shared::*;
 /// You can optionally pass the path to the migrations directory to this macro. When left
 /// unspecified, odegen will search for the migrations directory.
 /// If specified, the path should be relative to the directory where `Cargo.toml` resides.
+/// The directory may contain any of the migration layouts accepted by `migrations_in_directory`.
+///
+/// The migrations table used by the embedded module can be set with the `table_name` and
+/// `table_schema` options, e.g. `embed_migrations!("migrations", table_schema = "billing")`.
 macro_rules! embed_migrations {
     () => {
         #[allow(dead_code)]
@@ -21,6 +25,17 @@ macro_rules! embed_migrations {
         }
     };
 
+    ($($option:ident = $value:expr),+ $(,)?) => {
+        #[allow(dead_code)]
+        mod embedded_migrations {
+            use postgres_migrations::EmbedMigrations;
+
+            #[derive(EmbedMigrations)]
+            #[embed_migrations_options($($option=$value),+)]
+            struct _Dummy;
+        }
+    };
+
     ($migrations_path:expr) => {
         #[allow(dead_code)]
         mod embedded_migrations {
@@ -31,4 +46,15 @@ macro_rules! embed_migrations {
             struct _Dummy;
         }
     };
+
+    ($migrations_path:expr, $($option:ident = $value:expr),+ $(,)?) => {
+        #[allow(dead_code)]
+        mod embedded_migrations {
+            use postgres_migrations::EmbedMigrations;
+
+            #[derive(EmbedMigrations)]
+            #[embed_migrations_options(migrations_path=$migrations_path, $($option=$value),+)]
+            struct _Dummy;
+        }
+    };
 }